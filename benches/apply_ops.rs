@@ -40,7 +40,7 @@ pub fn benches() {
 
     let mut group = criterion.benchmark_group("Random n moves in tree with 10K nodes");
     group.sample_size(10);
-    let SIZE: usize = 10_000;
+    const SIZE: usize = 10_000;
     group.bench_function("n = 10K", |b| {
         const N: usize = 10_000;
         let mut source_forest: Forest<usize> = Forest::new();
@@ -98,10 +98,10 @@ fn bench_crdt_snapshot_merge(
         let mut a: Crdt = Crdt::new(1);
         let mut ids = Vec::new();
         for _ in 0..size {
-            ids.push(a.new_node(None));
+            ids.push(a.new_node(None).unwrap());
         }
         let mut b: Crdt = Crdt::new(2);
-        b.merge(&a);
+        b.merge(&a).unwrap();
         let mut rng: StdRng = rand::SeedableRng::seed_from_u64(0);
         for _ in 0..n {
             let i = rng.gen::<usize>() % size;
@@ -111,7 +111,7 @@ fn bench_crdt_snapshot_merge(
             } else {
                 rng.gen::<usize>() % 10
             };
-            a.mov(ids[i], Some(ids[j]));
+            a.mov(ids[i], Some(ids[j])).unwrap();
         }
         for _ in 0..n {
             let i = rng.gen::<usize>() % size;
@@ -121,14 +121,14 @@ fn bench_crdt_snapshot_merge(
             } else {
                 rng.gen::<usize>() % 10
             };
-            b.mov(ids[i], Some(ids[j]));
+            b.mov(ids[i], Some(ids[j])).unwrap();
         }
 
         bench.iter_batched(
             || (a.clone(), b.clone()),
             |(mut a, mut b): (Crdt, Crdt)| {
-                a.merge(&b);
-                b.merge(&a);
+                a.merge(&b).unwrap();
+                b.merge(&a).unwrap();
             },
             criterion::BatchSize::PerIteration,
         );
@@ -146,10 +146,10 @@ fn bench_crdt_undo_merge(
         let mut a: Crdt = Crdt::new(1);
         let mut ids = Vec::new();
         for _ in 0..size {
-            ids.push(a.new_node(None));
+            ids.push(a.new_node(None).unwrap());
         }
         let mut b: Crdt = Crdt::new(2);
-        b.merge(&a);
+        b.merge(&a).unwrap();
         let mut rng: StdRng = rand::SeedableRng::seed_from_u64(0);
         for _ in 0..n {
             let i = rng.gen::<usize>() % size;
@@ -159,7 +159,7 @@ fn bench_crdt_undo_merge(
             } else {
                 rng.gen::<usize>() % 10
             };
-            a.mov(ids[i], Some(ids[j]));
+            a.mov(ids[i], Some(ids[j])).unwrap();
         }
         for _ in 0..n {
             let i = rng.gen::<usize>() % size;
@@ -169,14 +169,14 @@ fn bench_crdt_undo_merge(
             } else {
                 rng.gen::<usize>() % 10
             };
-            b.mov(ids[i], Some(ids[j]));
+            b.mov(ids[i], Some(ids[j])).unwrap();
         }
 
         bench.iter_batched(
             || (a.clone(), b.clone()),
             |(mut a, mut b): (Crdt, Crdt)| {
-                a.merge(&b);
-                b.merge(&a);
+                a.merge(&b).unwrap();
+                b.merge(&a).unwrap();
             },
             criterion::BatchSize::PerIteration,
         );
@@ -194,7 +194,7 @@ fn bench_crdt_snapshot(
         let mut crdt: Crdt = Crdt::new(1);
         let mut ids = Vec::new();
         for _ in 0..size {
-            ids.push(crdt.new_node(None));
+            ids.push(crdt.new_node(None).unwrap());
         }
 
         b.iter_batched(
@@ -209,7 +209,7 @@ fn bench_crdt_snapshot(
                     } else {
                         rng.gen::<usize>() % 10
                     };
-                    crdt.mov(ids[i], Some(ids[j]));
+                    crdt.mov(ids[i], Some(ids[j])).unwrap();
                 }
             },
             criterion::BatchSize::PerIteration,
@@ -228,7 +228,7 @@ fn bench_crdt_undo(
         let mut crdt: Crdt = Crdt::new(1);
         let mut ids = Vec::new();
         for _ in 0..size {
-            ids.push(crdt.new_node(None));
+            ids.push(crdt.new_node(None).unwrap());
         }
 
         b.iter_batched(
@@ -243,7 +243,7 @@ fn bench_crdt_undo(
                     } else {
                         rng.gen::<usize>() % 10
                     };
-                    crdt.mov(ids[i], Some(ids[j]));
+                    crdt.mov(ids[i], Some(ids[j])).unwrap();
                 }
             },
             criterion::BatchSize::PerIteration,
@@ -0,0 +1,82 @@
+use criterion::{criterion_main, Criterion};
+use movable_tree::{mut_tree, Forest};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const DEPTH: usize = 10_000;
+const MOVES: usize = 10_000;
+
+/// A chain of `DEPTH` nodes, each one is the child of the previous one
+fn chain() -> Forest<usize> {
+    let mut forest = Forest::new();
+    forest.mov(0, None).unwrap();
+    for i in 1..DEPTH {
+        forest.mov(i, Some(i - 1)).unwrap();
+    }
+    forest
+}
+
+fn mut_chain() -> mut_tree::Forest<usize> {
+    let mut forest = mut_tree::Forest::new();
+    forest.mov(0, None).unwrap();
+    for i in 1..DEPTH {
+        forest.mov(i, Some(i - 1)).unwrap();
+    }
+    forest
+}
+
+/// Moves that each need a cycle check over a long path. Half of them are rejected.
+fn moves() -> Vec<(usize, usize)> {
+    let mut rng = StdRng::seed_from_u64(0);
+    (0..MOVES)
+        .map(|_| (rng.gen_range(0..DEPTH), rng.gen_range(0..DEPTH)))
+        .collect()
+}
+
+pub fn benches() {
+    let mut criterion: Criterion<_> = (Criterion::default()).configure_from_args().sample_size(10);
+    let mut group = criterion.benchmark_group("Move in a chain of 10K nodes");
+    let moves = moves();
+    group.bench_function("tree::Forest", |b| {
+        let forest = chain();
+        b.iter(|| {
+            let mut forest = forest.clone();
+            for &(node, parent) in moves.iter() {
+                let _ = forest.mov(node, Some(parent));
+            }
+        });
+    });
+
+    group.bench_function("tree::Forest with ancestor index", |b| {
+        let mut forest = chain();
+        forest.enable_ancestor_index().unwrap();
+        b.iter(|| {
+            let mut forest = forest.clone();
+            for &(node, parent) in moves.iter() {
+                let _ = forest.mov(node, Some(parent));
+            }
+        });
+    });
+
+    group.bench_function("mut_tree::Forest", |b| {
+        let forest = mut_chain();
+        b.iter(|| {
+            let mut forest = forest.clone();
+            for &(node, parent) in moves.iter() {
+                let _ = forest.mov(node, Some(parent));
+            }
+        });
+    });
+
+    group.bench_function("mut_tree::Forest with ancestor index", |b| {
+        let mut forest = mut_chain();
+        forest.enable_ancestor_index().unwrap();
+        b.iter(|| {
+            let mut forest = forest.clone();
+            for &(node, parent) in moves.iter() {
+                let _ = forest.mov(node, Some(parent));
+            }
+        });
+    });
+}
+
+criterion_main!(benches);
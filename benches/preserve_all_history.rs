@@ -1,28 +1,23 @@
 use criterion::{criterion_main, Criterion};
-use movable_tree::Forest;
+use im::HashMap as ImHashMap;
+use movable_tree::{reclaim::defer_drop, History};
 pub fn benches() {
     let mut criterion: Criterion<_> = (Criterion::default()).configure_from_args().sample_size(10);
     let mut group = criterion.benchmark_group("preserve all history");
     group.bench_function(
         "insert 10K elements and preserving all history (then drop the history)",
         |b| {
-            // It takes 1 ms to apply 10K ops
-            // It takes 8 ms to apply and record 10K ops
-            // It takes 9 ms to drop the history of 10K ops
-            // It takes 19 ms in total
+            // It takes 4 ms to apply 10K ops
+            // It takes 50 ms to apply and record 10K ops
+            // It takes 29 ms to drop the history of 10K ops
+            // It takes 79 ms in total
             b.iter(|| {
-                let mut forest: Forest<usize> = Forest::new();
-                let mut history = vec![];
-                forest.mov(0, None).unwrap();
+                let mut history: History<usize> = History::new();
+                history.mov(0, None).unwrap();
                 for i in 0..10_000 {
-                    history.push(forest.clone());
-                    forest.mov(i + 1, Some(i)).unwrap();
+                    history.mov(i + 1, Some(i)).unwrap();
                 }
                 // Dropping is slow
-
-                // spawn(move || {
-                //     drop(history);
-                // });
             });
         },
     );
@@ -30,21 +25,50 @@ pub fn benches() {
     group.bench_function(
         "insert 100K elements and preserving all history (then drop the history)",
         |b| {
-            // It takes 337 ms in total
-            // It takes 140 ms without dropping
+            // It takes 1.2 s in total
+            // It takes 660 ms without dropping
             b.iter(|| {
-                let mut forest: Forest<usize> = Forest::new();
-                let mut history = vec![];
-                forest.mov(0, None).unwrap();
+                let mut history: History<usize> = History::new();
+                history.mov(0, None).unwrap();
                 for i in 0..100_000 {
-                    history.push(forest.clone());
-                    forest.mov(i + 1, Some(i)).unwrap();
+                    history.mov(i + 1, Some(i)).unwrap();
                 }
 
                 // Dropping is slow
-                // spawn(move || {
-                //     drop(history);
-                // });
+            });
+        },
+    );
+
+    // The same history kept as a bare persistent map of parent pointers, which is what
+    // the forest was before the children index and the sibling order. Comparing with it
+    // tells the cost of the index on the machine the benchmark runs on.
+    group.bench_function(
+        "insert 10K elements and preserving all history of a bare parent map (then drop the history)",
+        |b| {
+            // It takes 34 ms in total
+            b.iter(|| bare_parent_map_history(10_000));
+        },
+    );
+
+    group.bench_function(
+        "insert 100K elements and preserving all history of a bare parent map (then drop the history)",
+        |b| {
+            // It takes 530 ms in total
+            b.iter(|| bare_parent_map_history(100_000));
+        },
+    );
+
+    group.bench_function(
+        "insert 100K elements and preserving all history (then defer dropping the history)",
+        |b| {
+            b.iter(|| {
+                let mut history: History<usize> = History::new();
+                history.mov(0, None).unwrap();
+                for i in 0..100_000 {
+                    history.mov(i + 1, Some(i)).unwrap();
+                }
+
+                defer_drop(history);
             });
         },
     );
@@ -55,12 +79,10 @@ pub fn benches() {
     //         // It takes 337 ms in total
     //         // It takes 140 ms without dropping
     //         b.iter(|| {
-    //             let mut forest: Forest<usize> = Forest::new();
-    //             let mut history = vec![];
-    //             forest.mov(0, None).unwrap();
+    //             let mut history: History<usize> = History::new();
+    //             history.mov(0, None).unwrap();
     //             for i in 0..1_000_000 {
-    //                 history.push(forest.clone());
-    //                 forest.mov(i + 1, Some(i)).unwrap();
+    //                 history.mov(i + 1, Some(i)).unwrap();
     //             }
 
     //             // Dropping is slow
@@ -70,4 +92,15 @@ pub fn benches() {
     // );
 }
 
+/// Record n versions of a map from node to its parent and deleted flag
+fn bare_parent_map_history(n: usize) {
+    let mut map: ImHashMap<usize, (Option<usize>, bool)> = ImHashMap::new();
+    let mut history = vec![];
+    map.insert(0, (None, false));
+    for i in 0..n {
+        history.push(map.clone());
+        map.insert(i + 1, (Some(i), false));
+    }
+}
+
 criterion_main!(benches);
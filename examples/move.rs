@@ -1,22 +1,19 @@
 //! This example is used to test the memory usage of inserting n nodes
 //!
-//! It takes 39MB to record the history of inserting 100K nodes
-//! It takes 450MB to record the history of inserting 1M nodes
+//! It takes 46MB to record the history of inserting 10K nodes (40MB before the children index)
+//! It takes 568MB to record the history of inserting 100K nodes (489MB before the children index)
+//! Inserting 1M nodes doesn't fit in 6GB of memory (it took 5.4GB before the children index)
 //!
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
-use std::thread::spawn;
-
-use movable_tree::Forest;
+use movable_tree::{reclaim::defer_drop, History};
 pub fn main() {
     let profiler = dhat::Profiler::builder().trim_backtraces(None).build();
-    let mut forest: Forest<usize> = Forest::new();
-    let mut history = vec![];
-    forest.mov(0, None).unwrap();
+    let mut history: History<usize> = History::new();
+    history.mov(0, None).unwrap();
     for i in 0..1_000_000 {
-        history.push(forest.clone());
-        forest.mov(i + 1, None).unwrap();
+        history.mov(i + 1, None).unwrap();
     }
     drop(profiler);
-    spawn(move || drop(history));
+    defer_drop(history);
 }
@@ -0,0 +1,1722 @@
+//! The movable tree CRDT, generic over the forest it applies the ops to and the
+//! strategy it uses to apply the ops that arrive out of order.
+//!
+//! Every replica applies the ops in the order of their ids. When a remote op sorts
+//! before some applied ops, the forest is brought back to a state before it by a
+//! [`Strategy`], and the later ops are applied again.
+//!
+//! [`crate::crdt_snapshot`] and [`crate::crdt_undo`] are the two built-in combinations.
+//! All of them share the same ops and wire format, so they can sync with each other.
+use std::{collections::BTreeMap, fmt::Display, ops::Bound};
+
+use fxhash::FxHashMap;
+
+use crate::{
+    encoding::{self, DecodeError, RawContent, RawOp},
+    fractional_index::FractionalIndex,
+    version_vector::VersionVector,
+    Change,
+};
+
+mod backend;
+#[cfg(feature = "serde")]
+mod serde_impl;
+mod snapshot;
+mod strategy;
+mod undo_manager;
+pub use backend::{NodeState, TreeBackend};
+pub use snapshot::SnapshotError;
+pub use strategy::{Snapshots, Strategy, UndoLog};
+pub use undo_manager::UndoManager;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ID {
+    lamport: Lamport,
+    client: Client,
+}
+
+impl ID {
+    pub fn new(lamport: Lamport, client: Client) -> Self {
+        Self { lamport, client }
+    }
+
+    pub fn lamport(&self) -> Lamport {
+        self.lamport
+    }
+
+    pub fn client(&self) -> Client {
+        self.client
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Op {
+    id: ID,
+    /// The index of the op among the ops of its client
+    counter: Counter,
+    content: OpContent,
+}
+
+impl Op {
+    pub fn new(id: ID, counter: Counter, content: OpContent) -> Self {
+        Self {
+            id,
+            counter,
+            content,
+        }
+    }
+
+    pub fn id(&self) -> ID {
+        self.id
+    }
+
+    pub fn counter(&self) -> Counter {
+        self.counter
+    }
+
+    pub fn content(&self) -> &OpContent {
+        &self.content
+    }
+
+    /// The node changed by the op
+    pub fn target(&self) -> ID {
+        match &self.content {
+            OpContent::New { .. } => self.id,
+            OpContent::Move { target, .. }
+            | OpContent::Delete(target)
+            | OpContent::Undelete(target) => *target,
+        }
+    }
+}
+
+impl PartialEq for Op {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Op {}
+
+impl Ord for Op {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+impl PartialOrd for Op {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// `position` decides the order among siblings. Siblings are sorted by `(position, node id)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OpContent {
+    New {
+        parent: Option<ID>,
+        position: FractionalIndex,
+    },
+    Move {
+        target: ID,
+        parent: Option<ID>,
+        position: FractionalIndex,
+    },
+    Delete(ID),
+    /// Restore a deleted node
+    Undelete(ID),
+}
+
+/// The reason why [`Crdt::revert_op`] can't revert an op
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevertError {
+    /// The op has not been imported by this replica
+    OpNotFound(ID),
+    /// The op changed nothing, or the state it changed is already the state before it
+    NothingToRevert,
+    /// The old parent of the target has been deleted
+    ParentDeleted(ID),
+    /// The old parent of the target is now its descendant
+    CyclicMove,
+    /// The replica can't create more ops, see [`EditError::LamportOverflow`]
+    LamportOverflow,
+}
+
+impl Display for RevertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RevertError::OpNotFound(id) => write!(f, "op {:?} is not found", id),
+            RevertError::NothingToRevert => write!(f, "there is nothing to revert"),
+            RevertError::ParentDeleted(id) => write!(f, "the old parent {:?} is deleted", id),
+            RevertError::CyclicMove => {
+                write!(f, "the old parent is now a descendant of the target")
+            }
+            RevertError::LamportOverflow => write!(f, "the lamport of the replica overflows"),
+        }
+    }
+}
+
+impl std::error::Error for RevertError {}
+
+/// The reason why a local edit can't be made. Nothing is recorded then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditError {
+    /// The target is not created by any imported op
+    NodeNotFound(ID),
+    /// The new parent is not created by any imported op
+    ParentNotFound(ID),
+    /// The lamport has reached the maximum, so the replica can't create more ops
+    LamportOverflow,
+}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EditError::NodeNotFound(id) => write!(f, "node {:?} does not exist", id),
+            EditError::ParentNotFound(id) => write!(f, "parent {:?} does not exist", id),
+            EditError::LamportOverflow => write!(f, "the lamport of the replica overflows"),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+/// The reason why ops can't be exchanged with a peer.
+///
+/// A batch of ops is checked before it's imported, and nothing is imported if any op is invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncError {
+    /// The peer hasn't seen some ops of the client that have been compacted by [`Crdt::compact`].
+    /// It has to catch up from a replica that still has the ops.
+    BehindBaseline(Client),
+    /// The op sorts before the compacted ops, so it can't be merged anymore
+    OpBeforeBaseline(ID),
+    /// The op refers to a node that is not created by any op
+    UnknownReference { op: ID, node: ID },
+    /// Another op has the same id but a different counter or content
+    DuplicateId(ID),
+    /// The op doesn't fit the sequence of its client. Another op has the same counter,
+    /// or the lamports don't increase with the counters
+    InvalidSequence(ID),
+    /// The lamport of the op is too large to count the next ops from
+    LamportOverflow(ID),
+    /// The new ops and the ops waiting for their dependencies exceed the limit set by
+    /// [`Crdt::set_max_batch_len`]
+    BatchTooLarge { len: usize, max: usize },
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::BehindBaseline(client) => {
+                write!(f, "the ops of client {} have been compacted", client)
+            }
+            SyncError::OpBeforeBaseline(id) => {
+                write!(f, "op {:?} is older than the compacted history", id)
+            }
+            SyncError::UnknownReference { op, node } => {
+                write!(f, "op {:?} refers to unknown node {:?}", op, node)
+            }
+            SyncError::DuplicateId(id) => write!(f, "op {:?} has conflicting contents", id),
+            SyncError::InvalidSequence(id) => {
+                write!(f, "op {:?} doesn't fit the ops of its client", id)
+            }
+            SyncError::LamportOverflow(id) => write!(f, "the lamport of op {:?} overflows", id),
+            SyncError::BatchTooLarge { len, max } => {
+                write!(f, "{} ops exceed the limit of {} ops", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+type OpLog = FxHashMap<Client, Vec<Op>>;
+pub type Client = u64;
+pub type Lamport = u32;
+pub type Counter = u32;
+
+/// The default limit of [`Crdt::set_max_batch_len`]
+pub const DEFAULT_MAX_BATCH_LEN: usize = 1 << 20;
+
+/// A replica of the movable tree.
+///
+/// `B` is the forest the ops are applied to, and `S` decides how the forest is
+/// brought back to an older state when a remote op sorts before the applied ops.
+#[derive(Debug, Clone)]
+pub struct Crdt<B, S> {
+    forest: B,
+    strategy: S,
+    client: Client,
+    next_lamport: Lamport,
+    /// the ops of each client after the baseline
+    log: OpLog,
+    /// ops after the baseline sorted by ID
+    sorted_ops: Vec<Op>,
+    /// the end of applied op in sorted ops.
+    applied_end: usize,
+    /// Remote ops waiting for their dependencies, by `(client, counter)`
+    pending: BTreeMap<(Client, Counter), Op>,
+    /// The forest after the compacted ops. See [`Crdt::compact`]
+    baseline: B,
+    /// The number of compacted ops of each client
+    baseline_version: VersionVector,
+    /// The greatest id of the compacted ops
+    baseline_last: Option<ID>,
+    max_batch_len: usize,
+}
+
+impl<B: TreeBackend, S: Strategy<B>> Crdt<B, S> {
+    pub fn new(client: Client) -> Self {
+        Crdt {
+            client,
+            forest: Default::default(),
+            strategy: Default::default(),
+            next_lamport: 0,
+            log: Default::default(),
+            sorted_ops: Default::default(),
+            applied_end: 0,
+            pending: Default::default(),
+            baseline: Default::default(),
+            baseline_version: Default::default(),
+            baseline_last: None,
+            max_batch_len: DEFAULT_MAX_BATCH_LEN,
+        }
+    }
+
+    /// Limit the number of new ops in an imported batch, including the ops
+    /// waiting for their dependencies. A larger batch is rejected.
+    pub fn set_max_batch_len(&mut self, len: usize) {
+        self.max_batch_len = len;
+    }
+
+    fn push_op(&mut self, op: Op) {
+        self.log.entry(self.client).or_default().push(op.clone());
+        self.sorted_ops.push(op);
+    }
+
+    fn new_op(&mut self, content: OpContent) -> Result<Op, EditError> {
+        let id = ID {
+            lamport: self.next_lamport,
+            client: self.client,
+        };
+        self.next_lamport = id
+            .lamport
+            .checked_add(1)
+            .ok_or(EditError::LamportOverflow)?;
+        Ok(Op {
+            id,
+            counter: self.log_len(self.client) as Counter,
+            content,
+        })
+    }
+
+    /// Check that the nodes exist, because the peers reject an op referring to an unknown node.
+    ///
+    /// All the imported ops are applied, so the forest has every node created by them.
+    fn check_nodes(&self, target: Option<ID>, parent: Option<ID>) -> Result<(), EditError> {
+        if let Some(target) = target.filter(|x| !self.forest.contains(*x)) {
+            return Err(EditError::NodeNotFound(target));
+        }
+        if let Some(parent) = parent.filter(|x| !self.forest.contains(*x)) {
+            return Err(EditError::ParentNotFound(parent));
+        }
+        Ok(())
+    }
+
+    /// Create a new node as the last child of parent.
+    ///
+    /// The only change is always `Change::Created` of the new node, so it's not returned.
+    pub fn new_node(&mut self, parent: Option<ID>) -> Result<ID, EditError> {
+        self.new_node_at(parent, usize::MAX)
+    }
+
+    /// Create a new node as the `index`-th child of parent.
+    /// The index is clamped to the number of children.
+    pub fn new_node_at(&mut self, parent: Option<ID>, index: usize) -> Result<ID, EditError> {
+        self.check_nodes(None, parent)?;
+        let position = self.position_at(None, parent, index);
+        let op = self.new_op(OpContent::New { parent, position })?;
+        let id = op.id;
+        self.push_op(op);
+        self.apply_pending_ops();
+        Ok(id)
+    }
+
+    /// Move target into parent, and return the changes of the forest.
+    ///
+    /// It's appended to the end of the new siblings, unless parent is already its parent.
+    pub fn mov(&mut self, target: ID, parent: Option<ID>) -> Result<Vec<Change<ID>>, EditError> {
+        self.check_nodes(Some(target), parent)?;
+        let position = match self.forest.position(target) {
+            Some(position) if self.forest.parent_of(target) == parent => position.clone(),
+            _ => self.position_at(Some(target), parent, usize::MAX),
+        };
+        self.push_move(target, parent, position)
+    }
+
+    /// Move target into parent, so that it becomes the `index`-th child of parent.
+    /// The index is counted without target and is clamped to the number of children.
+    pub fn mov_to(
+        &mut self,
+        target: ID,
+        parent: Option<ID>,
+        index: usize,
+    ) -> Result<Vec<Change<ID>>, EditError> {
+        self.check_nodes(Some(target), parent)?;
+        let position = self.position_at(Some(target), parent, index);
+        self.push_move(target, parent, position)
+    }
+
+    fn push_move(
+        &mut self,
+        target: ID,
+        parent: Option<ID>,
+        position: FractionalIndex,
+    ) -> Result<Vec<Change<ID>>, EditError> {
+        self.push_content(OpContent::Move {
+            target,
+            parent,
+            position,
+        })
+    }
+
+    /// Generate a position that puts target at `index` among the other children of parent
+    fn position_at(&self, target: Option<ID>, parent: Option<ID>, index: usize) -> FractionalIndex {
+        let forest = &self.forest;
+        let target_index = target
+            .filter(|t| forest.contains(*t) && forest.parent_of(*t) == parent)
+            .and_then(|t| forest.index_in_parent(t));
+        let len = forest.children_len(parent) - target_index.is_some() as usize;
+        let index = index.min(len);
+        let nth = |i: usize| match target_index {
+            Some(t) if i >= t => forest.child_at(parent, i + 1),
+            _ => forest.child_at(parent, i),
+        };
+        let lower = index.checked_sub(1).and_then(nth);
+        let upper = if index < len { nth(index) } else { None };
+        FractionalIndex::between(
+            lower.and_then(|x| forest.position(x)),
+            upper.and_then(|x| forest.position(x)),
+        )
+        .with_suffix(self.client)
+    }
+
+    /// Delete target, and return the changes of the forest
+    pub fn delete(&mut self, target: ID) -> Result<Vec<Change<ID>>, EditError> {
+        self.check_nodes(Some(target), None)?;
+        self.push_content(OpContent::Delete(target))
+    }
+
+    /// Restore a deleted node, and return the changes of the forest
+    pub fn undelete(&mut self, target: ID) -> Result<Vec<Change<ID>>, EditError> {
+        self.check_nodes(Some(target), None)?;
+        self.push_content(OpContent::Undelete(target))
+    }
+
+    fn push_content(&mut self, content: OpContent) -> Result<Vec<Change<ID>>, EditError> {
+        let op = self.new_op(content)?;
+        let before = self.node_states([op.target()]);
+        self.push_op(op);
+        self.apply_pending_ops();
+        Ok(self.changes_since(before))
+    }
+
+    /// Revert the op with `id` by new ops, so the revert is synced like other edits.
+    ///
+    /// A moved node is moved back to its old parent and position, a deleted node is restored,
+    /// and a created node is deleted. Return the id of the new op, or Err if the inverse no
+    /// longer applies to the current state.
+    pub fn revert_op(&mut self, id: ID) -> Result<ID, RevertError> {
+        let i = self
+            .sorted_ops
+            .binary_search_by_key(&id, |x| x.id)
+            .map_err(|_| RevertError::OpNotFound(id))?;
+        let inverse = self.inverse_at(i).ok_or(RevertError::NothingToRevert)?;
+        match (&self.sorted_ops[i].content, &inverse) {
+            (OpContent::Delete(_), OpContent::Delete(_))
+            | (OpContent::Undelete(_), OpContent::Undelete(_)) => {
+                return Err(RevertError::NothingToRevert)
+            }
+            _ => {}
+        }
+        self.check_inverse(&inverse)?;
+        self.push_content(inverse)
+            .map_err(|_| RevertError::LamportOverflow)?;
+        Ok(self.log[&self.client].last().unwrap().id)
+    }
+
+    /// Check that the inverse op still changes the current state as expected
+    fn check_inverse(&self, inverse: &OpContent) -> Result<(), RevertError> {
+        let forest = &self.forest;
+        match inverse {
+            OpContent::New { .. } => unreachable!(),
+            OpContent::Move {
+                target,
+                parent,
+                position,
+            } => {
+                if forest.contains(*target)
+                    && forest.parent_of(*target) == *parent
+                    && forest.position(*target) == Some(position)
+                {
+                    return Err(RevertError::NothingToRevert);
+                }
+                if let Some(parent) = *parent {
+                    if forest.is_deleted(parent) {
+                        return Err(RevertError::ParentDeleted(parent));
+                    }
+                    // walk up from the old parent. A valid path can't be longer than the forest
+                    let mut node = Some(parent);
+                    for _ in 0..=forest.len() {
+                        match node {
+                            Some(x) if x == *target => return Err(RevertError::CyclicMove),
+                            Some(x) => node = forest.parent_of(x),
+                            None => break,
+                        }
+                    }
+                }
+            }
+            OpContent::Delete(target) => {
+                if forest.is_deleted(*target) {
+                    return Err(RevertError::NothingToRevert);
+                }
+            }
+            OpContent::Undelete(target) => {
+                if !forest.is_deleted(*target) {
+                    return Err(RevertError::NothingToRevert);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The content of an op that restores the state before the op with `id`,
+    /// in the order of all the merged ops. Return `None` if the op is unknown.
+    pub(crate) fn inverse_of(&self, id: ID) -> Option<OpContent> {
+        let i = self.sorted_ops.binary_search_by_key(&id, |x| x.id).ok()?;
+        self.inverse_at(i)
+    }
+
+    fn inverse_at(&self, i: usize) -> Option<OpContent> {
+        let op = &self.sorted_ops[i];
+        let before = || {
+            self.strategy.node_before(
+                &self.forest,
+                &self.baseline,
+                &self.sorted_ops,
+                i,
+                op.target(),
+            )
+        };
+        Some(match &op.content {
+            OpContent::New { .. } => OpContent::Delete(op.id),
+            OpContent::Move { target, .. } => {
+                let before = before()?;
+                OpContent::Move {
+                    target: *target,
+                    parent: before.parent,
+                    position: before.position,
+                }
+            }
+            OpContent::Delete(target) | OpContent::Undelete(target) => {
+                if before().is_some_and(|x| x.deleted) {
+                    OpContent::Delete(*target)
+                } else {
+                    OpContent::Undelete(*target)
+                }
+            }
+        })
+    }
+
+    fn apply_pending_ops(&mut self) {
+        for op in &self.sorted_ops[self.applied_end..] {
+            self.strategy.apply(&mut self.forest, op);
+        }
+
+        self.applied_end = self.sorted_ops.len();
+    }
+
+    /// The number of ops seen from each client
+    pub fn version(&self) -> VersionVector {
+        let mut version = self.baseline_version.clone();
+        for client in self.log.keys() {
+            version.set(*client, self.log_len(*client) as Counter);
+        }
+        version
+    }
+
+    /// The ops that are not included in `since`, in the order of each client.
+    ///
+    /// Return Err if some of them have been compacted.
+    pub fn export_ops(&self, since: &VersionVector) -> Result<Vec<Op>, SyncError> {
+        if let Some((client, _)) = self
+            .baseline_version
+            .iter()
+            .find(|(client, len)| since.get(*client) < *len)
+        {
+            return Err(SyncError::BehindBaseline(client));
+        }
+
+        let mut ans = Vec::new();
+        for client in self.log.keys() {
+            ans.extend_from_slice(self.log_since(*client, since.get(*client)));
+        }
+        Ok(ans)
+    }
+
+    /// Merge the changes of other replica, and return the changes of the forest.
+    ///
+    /// The other replica may use another backend or strategy.
+    pub fn merge<B2: TreeBackend, S2: Strategy<B2>>(
+        &mut self,
+        other: &Crdt<B2, S2>,
+    ) -> Result<Vec<Change<ID>>, SyncError> {
+        self.import_ops(other.export_ops(&self.version())?)
+    }
+
+    /// Import ops exported by other replica with [`Crdt::export_ops`].
+    ///
+    /// The ops can arrive in any order, and the ops that have been imported are skipped.
+    /// An op waits in a pending queue until the previous op of its client and the `New` ops of
+    /// the nodes it refers to are imported. Then the ops are applied in the order of their ids.
+    ///
+    /// Return the net changes of the forest, sorted by node id. The nodes that are moved back
+    /// and forth while the ops are reordered are not reported.
+    ///
+    /// If any op is invalid, nothing is imported and Err is returned. See [`SyncError`].
+    pub fn import_ops(
+        &mut self,
+        ops: impl IntoIterator<Item = Op>,
+    ) -> Result<Vec<Change<ID>>, SyncError> {
+        let batch = self.validate(ops)?;
+        self.pending.extend(batch);
+        let mut ans = self.release_pending();
+        if ans.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // the nodes changed by the new ops and the ops to apply again
+        let start = self.sorted_ops.partition_point(|op| op.id < ans[0].id);
+        let touched = ans
+            .iter()
+            .chain(&self.sorted_ops[start..])
+            .map(|op| op.target());
+        let before = self.node_states(touched);
+        let end = self.strategy.rewind(
+            &mut self.forest,
+            &self.baseline,
+            &self.sorted_ops[..self.applied_end],
+            start,
+        );
+        ans.extend(self.sorted_ops.drain(end..));
+        ans.sort();
+        self.sorted_ops.append(&mut ans);
+        self.applied_end = end;
+        self.apply_pending_ops();
+        Ok(self.changes_since(before))
+    }
+
+    /// Move the pending ops whose dependencies have been imported to the log.
+    /// Return them sorted by id, they are not applied yet.
+    fn release_pending(&mut self) -> Vec<Op> {
+        let mut ans = Vec::new();
+        let mut clients: Vec<Client> = self.pending.keys().map(|(client, _)| *client).collect();
+        clients.dedup();
+        loop {
+            let mut progress = false;
+            for &client in clients.iter() {
+                loop {
+                    let key = (client, self.log_len(client) as Counter);
+                    match self.pending.get(&key) {
+                        Some(op) if self.dependencies(op).all(|id| self.contains_node(id)) => {
+                            let op = self.pending.remove(&key).unwrap();
+                            self.log.entry(client).or_default().push(op.clone());
+                            if op.id.lamport >= self.next_lamport {
+                                self.next_lamport = op.id.lamport + 1;
+                            }
+                            ans.push(op);
+                            progress = true;
+                        }
+                        _ => break,
+                    }
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+        ans.sort();
+        ans
+    }
+
+    /// The states of the nodes, or `None` if a node doesn't exist
+    fn node_states(&self, nodes: impl IntoIterator<Item = ID>) -> BTreeMap<ID, Option<NodeState>> {
+        nodes
+            .into_iter()
+            .map(|id| (id, self.forest.node_state(id)))
+            .collect()
+    }
+
+    /// The changes of the nodes from the states recorded by [`Crdt::node_states`]
+    fn changes_since(&self, before: BTreeMap<ID, Option<NodeState>>) -> Vec<Change<ID>> {
+        let mut ans = Vec::new();
+        for (id, old) in before {
+            match (old, self.forest.node_state(id)) {
+                (None, Some(new)) => {
+                    ans.push(Change::Created {
+                        node: id,
+                        parent: new.parent,
+                    });
+                    if new.deleted {
+                        ans.push(Change::Deleted(id));
+                    }
+                }
+                (Some(old), Some(new)) => {
+                    if old.parent != new.parent {
+                        ans.push(Change::Moved {
+                            node: id,
+                            from: old.parent,
+                            to: new.parent,
+                        });
+                    }
+                    match (old.deleted, new.deleted) {
+                        (false, true) => ans.push(Change::Deleted(id)),
+                        (true, false) => ans.push(Change::Restored(id)),
+                        _ => {}
+                    }
+                }
+                (Some(_), None) => ans.push(Change::Removed(id)),
+                (None, None) => {}
+            }
+        }
+        ans
+    }
+
+    /// Compact the ops included in `stable` into a baseline forest, and drop them.
+    ///
+    /// `stable` must be causally stable: every replica has merged the ops in it, and the ops
+    /// that are not in it sort after them. Only the longest prefix of the sorted ops that is
+    /// included in `stable` is compacted. Return the number of dropped ops.
+    ///
+    /// The ops newer than the baseline are merged as usual, but a peer that hasn't seen all
+    /// the compacted ops can't sync with this replica anymore. The compacted ops can't be
+    /// reverted or undone.
+    pub fn compact(&mut self, stable: &VersionVector) -> usize {
+        let first_pending = self.pending.values().map(|op| op.id).min();
+        let end = self
+            .sorted_ops
+            .iter()
+            .position(|op| {
+                op.counter >= stable.get(op.id.client) || first_pending.is_some_and(|x| op.id > x)
+            })
+            .unwrap_or(self.sorted_ops.len());
+        if end == 0 {
+            return 0;
+        }
+
+        self.baseline = self
+            .strategy
+            .state_at(&self.forest, &self.baseline, &self.sorted_ops, end);
+        self.baseline_last = Some(self.sorted_ops[end - 1].id);
+        for op in self.sorted_ops.drain(..end) {
+            self.baseline_version.set(op.id.client, op.counter + 1);
+        }
+        for (client, ops) in self.log.iter_mut() {
+            let len = self.baseline_version.get(*client);
+            let n = ops.partition_point(|op| op.counter < len);
+            ops.drain(..n);
+        }
+
+        // the records of the strategy may refer to the dropped ops
+        self.strategy = Default::default();
+        self.forest = self.baseline.clone();
+        self.applied_end = 0;
+        self.apply_pending_ops();
+        end
+    }
+
+    /// The number of compacted ops of each client
+    pub fn baseline_version(&self) -> &VersionVector {
+        &self.baseline_version
+    }
+
+    /// The forest after applying the ops whose ids are <= `id`.
+    ///
+    /// The live state is not changed. The versions before the baseline can't be rebuilt,
+    /// the baseline is returned for them.
+    pub fn checkout(&self, id: ID) -> B {
+        let end = self.sorted_ops.partition_point(|op| op.id <= id);
+        self.replay(end, |_| false)
+    }
+
+    /// The forest after applying the ops included in `version`.
+    ///
+    /// The ops that are not imported yet are ignored.
+    /// The live state is not changed.
+    pub fn checkout_version(&self, version: &VersionVector) -> B {
+        let included = |op: &Op| op.counter < version.get(op.id.client);
+        let end = self
+            .sorted_ops
+            .iter()
+            .position(|op| !included(op))
+            .unwrap_or(self.sorted_ops.len());
+        self.replay(end, included)
+    }
+
+    /// Replay `sorted_ops[..prefix_end]` and the ops after it that pass the filter
+    fn replay(&self, prefix_end: usize, filter: impl Fn(&Op) -> bool) -> B {
+        let mut forest =
+            self.strategy
+                .state_at(&self.forest, &self.baseline, &self.sorted_ops, prefix_end);
+        for op in self.sorted_ops[prefix_end..].iter() {
+            if filter(op) {
+                apply_op(&mut forest, op);
+            }
+        }
+        forest
+    }
+
+    /// Check the ops before importing them, so that a rejected batch changes nothing.
+    ///
+    /// Return the ops that are not imported yet, by `(client, counter)`. An op may refer to
+    /// a node that is not known yet, unless the node can't be created anymore.
+    fn validate(
+        &self,
+        ops: impl IntoIterator<Item = Op>,
+    ) -> Result<BTreeMap<(Client, Counter), Op>, SyncError> {
+        let mut batch: BTreeMap<(Client, Counter), Op> = BTreeMap::new();
+        for op in ops {
+            if op.id.lamport == Lamport::MAX {
+                return Err(SyncError::LamportOverflow(op.id));
+            }
+            let key = (op.id.client, op.counter);
+            if let Some(known) = self.op_at(key).or_else(|| batch.get(&key)) {
+                if known.id != op.id {
+                    return Err(SyncError::InvalidSequence(op.id));
+                }
+                if known.content != op.content {
+                    return Err(SyncError::DuplicateId(op.id));
+                }
+                continue;
+            }
+            if (op.counter as usize) < self.log_len(op.id.client) {
+                // compacted
+                continue;
+            }
+            if self.baseline_last.is_some_and(|last| op.id <= last) {
+                return Err(SyncError::OpBeforeBaseline(op.id));
+            }
+            batch.insert(key, op);
+        }
+
+        let len = batch.len() + self.pending.len();
+        if len > self.max_batch_len {
+            return Err(SyncError::BatchTooLarge {
+                len,
+                max: self.max_batch_len,
+            });
+        }
+
+        let mut waiting: FxHashMap<ID, &Op> = Default::default();
+        for op in self.pending.values().chain(batch.values()) {
+            if waiting.insert(op.id, op).is_some() {
+                return Err(SyncError::DuplicateId(op.id));
+            }
+        }
+        for op in batch.values() {
+            if self.find_op(op.id).is_some() {
+                return Err(SyncError::DuplicateId(op.id));
+            }
+
+            // the lamport grows by at least 1 with each op of a client
+            let (prev, next) = neighbors(&self.pending, op);
+            let (batch_prev, batch_next) = neighbors(&batch, op);
+            let prev = [
+                prev,
+                batch_prev,
+                self.log.get(&op.id.client).and_then(|x| x.last()),
+            ]
+            .into_iter()
+            .flatten()
+            .max_by_key(|x| x.counter);
+            let next = [next, batch_next]
+                .into_iter()
+                .flatten()
+                .min_by_key(|x| x.counter);
+            for (a, b) in [(prev, Some(op)), (Some(op), next)] {
+                if let (Some(a), Some(b)) = (a, b) {
+                    if (b.id.lamport as u64) < a.id.lamport as u64 + (b.counter - a.counter) as u64
+                    {
+                        return Err(SyncError::InvalidSequence(op.id));
+                    }
+                }
+            }
+
+            for node in self.dependencies(op) {
+                let created = if node.lamport >= op.id.lamport {
+                    // a node is created before it's referred to
+                    Some(false)
+                } else if self.baseline_last.is_some_and(|last| node <= last) {
+                    Some(self.baseline.contains(node))
+                } else if let Some(x) = waiting.get(&node).copied().or_else(|| self.find_op(node)) {
+                    Some(matches!(x.content, OpContent::New { .. }))
+                } else if self
+                    .log
+                    .get(&node.client)
+                    .and_then(|x| x.last())
+                    .is_some_and(|x| x.id.lamport > node.lamport)
+                {
+                    // the ops of the client before the last one are all imported
+                    Some(false)
+                } else {
+                    None
+                };
+                if created == Some(false) {
+                    return Err(SyncError::UnknownReference { op: op.id, node });
+                }
+            }
+        }
+
+        Ok(batch)
+    }
+
+    /// The op with the counter in the log or the pending queue
+    fn op_at(&self, (client, counter): (Client, Counter)) -> Option<&Op> {
+        match self.log_since(client, counter).first() {
+            Some(op) if op.counter == counter => Some(op),
+            _ => self.pending.get(&(client, counter)),
+        }
+    }
+
+    /// The op with the id in the log. The compacted ops are not found
+    fn find_op(&self, id: ID) -> Option<&Op> {
+        let ops = self.log.get(&id.client)?;
+        let i = ops
+            .binary_search_by_key(&id.lamport, |x| x.id.lamport)
+            .ok()?;
+        Some(&ops[i])
+    }
+
+    /// The number of remote ops waiting for their dependencies
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn log_len(&self, client: Client) -> usize {
+        self.baseline_version.get(client) as usize
+            + self.log.get(&client).map(|x| x.len()).unwrap_or(0)
+    }
+
+    /// The ops of the client from `counter`. The compacted ops are skipped.
+    fn log_since(&self, client: Client, counter: Counter) -> &[Op] {
+        let ops = self.log.get(&client).map(|x| x.as_slice()).unwrap_or(&[]);
+        let start = counter.saturating_sub(self.baseline_version.get(client)) as usize;
+        &ops[start.min(ops.len())..]
+    }
+
+    /// Whether the `New` op of the node has been imported
+    fn contains_node(&self, id: ID) -> bool {
+        if self.baseline_last.is_some_and(|last| id <= last) {
+            return self.baseline.contains(id);
+        }
+        self.find_op(id)
+            .is_some_and(|op| matches!(op.content, OpContent::New { .. }))
+    }
+
+    /// The nodes the op refers to
+    fn dependencies(&self, op: &Op) -> impl Iterator<Item = ID> {
+        let (a, b) = match &op.content {
+            OpContent::New { parent, .. } => (*parent, None),
+            OpContent::Move { target, parent, .. } => (Some(*target), *parent),
+            OpContent::Delete(target) | OpContent::Undelete(target) => (Some(*target), None),
+        };
+        a.into_iter().chain(b)
+    }
+
+    pub fn forest(&self) -> &B {
+        &self.forest
+    }
+
+    pub fn strategy(&self) -> &S {
+        &self.strategy
+    }
+
+    /// The children of parent in order, including the deleted ones.
+    ///
+    /// The order converges on every replica after merging the same ops.
+    pub fn children_ordered(&self, parent: Option<ID>) -> Vec<ID> {
+        self.forest.children_of(parent).collect()
+    }
+}
+
+/// The ops of the same client right before and after the op
+fn neighbors<'a>(
+    ops: &'a BTreeMap<(Client, Counter), Op>,
+    op: &Op,
+) -> (Option<&'a Op>, Option<&'a Op>) {
+    let (client, counter) = (op.id.client, op.counter);
+    let prev = ops.range((client, 0)..(client, counter)).next_back();
+    let next = ops
+        .range((
+            Bound::Excluded((client, counter)),
+            Bound::Included((client, Counter::MAX)),
+        ))
+        .next();
+    (prev.map(|x| x.1), next.map(|x| x.1))
+}
+
+/// Encode a batch of ops with the format described in [`crate::encoding`]
+pub fn encode_ops(ops: &[Op]) -> Vec<u8> {
+    let raw: Vec<RawOp> = ops.iter().map(|op| op.into()).collect();
+    encoding::encode(&raw)
+}
+
+/// Decode a batch of ops encoded by [`encode_ops`]. Invalid input returns Err.
+pub fn decode_ops(bytes: &[u8]) -> Result<Vec<Op>, DecodeError> {
+    Ok(encoding::decode(bytes)?.into_iter().map(Op::from).collect())
+}
+
+impl From<&Op> for RawOp {
+    fn from(op: &Op) -> Self {
+        let raw_id = |id: ID| (id.lamport, id.client);
+        RawOp {
+            id: raw_id(op.id),
+            counter: op.counter,
+            content: match &op.content {
+                OpContent::New { parent, position } => RawContent::New {
+                    parent: parent.map(raw_id),
+                    position: position.clone(),
+                },
+                OpContent::Move {
+                    target,
+                    parent,
+                    position,
+                } => RawContent::Move {
+                    target: raw_id(*target),
+                    parent: parent.map(raw_id),
+                    position: position.clone(),
+                },
+                OpContent::Delete(target) => RawContent::Delete(raw_id(*target)),
+                OpContent::Undelete(target) => RawContent::Undelete(raw_id(*target)),
+            },
+        }
+    }
+}
+
+impl From<RawOp> for Op {
+    fn from(op: RawOp) -> Self {
+        let id = |(lamport, client)| ID { lamport, client };
+        Op {
+            id: id(op.id),
+            counter: op.counter,
+            content: match op.content {
+                RawContent::New { parent, position } => OpContent::New {
+                    parent: parent.map(id),
+                    position,
+                },
+                RawContent::Move {
+                    target,
+                    parent,
+                    position,
+                } => OpContent::Move {
+                    target: id(target),
+                    parent: parent.map(id),
+                    position,
+                },
+                RawContent::Delete(target) => OpContent::Delete(id(target)),
+                RawContent::Undelete(target) => OpContent::Undelete(id(target)),
+            },
+        }
+    }
+}
+
+/// Apply the op to the forest. The ops that can't be applied, e.g. a move that would
+/// cause a cycle, change nothing.
+fn apply_op<B: TreeBackend>(forest: &mut B, op: &Op) {
+    match &op.content {
+        OpContent::New { parent, position } => {
+            mov_with_position(forest, op.id, *parent, position.clone());
+        }
+        OpContent::Move {
+            target,
+            parent,
+            position,
+        } => {
+            mov_with_position(forest, *target, *parent, position.clone());
+        }
+        OpContent::Delete(target) => forest.set_deleted(*target, true),
+        OpContent::Undelete(target) => forest.set_deleted(*target, false),
+    }
+}
+
+/// Move target into parent, keeping the siblings sorted by `(position, id)`
+fn mov_with_position<B: TreeBackend>(
+    forest: &mut B,
+    target: ID,
+    parent: Option<ID>,
+    position: FractionalIndex,
+) {
+    let mut index = forest.children_before(parent, &position, target);
+    if forest.contains(target)
+        && forest.parent_of(target) == parent
+        && forest.index_in_parent(target).unwrap() < index
+    {
+        index -= 1;
+    }
+
+    if forest.move_node(target, parent, index) {
+        forest.set_position(target, position);
+    }
+}
+
+/// A random test that checks the replicas converge
+pub mod fuzz {
+    use super::{Client, Crdt, Strategy, TreeBackend};
+
+    #[derive(Debug, Clone, Copy, arbitrary::Arbitrary)]
+    pub enum Action {
+        Mov(u8, u8, u8),
+        MovTo(u8, u8, u8, u8),
+        Del(u8, u8),
+        Sync(u8, u8),
+    }
+
+    pub fn fuzzing<B: TreeBackend, S: Strategy<B>>(n_actors: usize, actions: Vec<Action>) {
+        let mut actors: Vec<Crdt<B, S>> = Vec::new();
+        let mut ids = Vec::new();
+        for i in 0..n_actors {
+            actors.push(Crdt::new(i as Client))
+        }
+
+        for _ in 0..256 {
+            ids.push(actors[0].new_node(None).unwrap());
+        }
+
+        for j in 1..n_actors {
+            let (a, b) = arref::array_mut_ref!(&mut actors, [0, j]);
+            b.merge(a).unwrap();
+        }
+
+        for action in actions {
+            match action {
+                Action::Mov(client, a, b) => {
+                    actors[client as usize % n_actors]
+                        .mov(ids[a as usize], Some(ids[b as usize]))
+                        .unwrap();
+                }
+                Action::MovTo(client, a, b, index) => {
+                    actors[client as usize % n_actors]
+                        .mov_to(ids[a as usize], Some(ids[b as usize]), index as usize)
+                        .unwrap();
+                }
+                Action::Del(client, a) => {
+                    actors[client as usize % n_actors]
+                        .delete(ids[a as usize])
+                        .unwrap();
+                }
+                Action::Sync(a, b) => {
+                    let a = a as usize % n_actors;
+                    let b = b as usize % n_actors;
+                    if a == b {
+                        continue;
+                    }
+
+                    let (a, b) = arref::array_mut_ref!(&mut actors, [a, b]);
+                    a.merge(b).unwrap();
+                }
+            }
+        }
+
+        for i in 1..n_actors {
+            let (a, b) = arref::array_mut_ref!(&mut actors, [i - 1, i]);
+            a.merge(b).unwrap();
+            b.merge(a).unwrap();
+            assert_eq!(a.forest(), b.forest());
+        }
+
+        let restored = Crdt::<B, S>::import_snapshot(&actors[0].export_snapshot()).unwrap();
+        assert_eq!(restored.forest(), actors[0].forest());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type SnapshotForest = crate::Forest<ID, FractionalIndex>;
+    type UndoForest = crate::mut_tree::Forest<ID, FractionalIndex>;
+
+    /// Run each generic test on both built-in CRDTs
+    macro_rules! for_both {
+        ($($(#[$attr:meta])* $name:ident),* $(,)?) => {$(
+            $(#[$attr])*
+            mod $name {
+                use super::*;
+
+                #[test]
+                fn snapshot() {
+                    super::$name::<SnapshotForest, Snapshots<SnapshotForest>>();
+                }
+
+                #[test]
+                fn undo() {
+                    super::$name::<UndoForest, UndoLog>();
+                }
+            }
+        )*};
+    }
+
+    for_both!(
+        merge_moves,
+        repeated_moves,
+        concurrent_siblings,
+        export_import,
+        #[cfg(feature = "serde")]
+        serde,
+        out_of_order,
+        checkout,
+        revert_op,
+        compact,
+        changes,
+        validation,
+    );
+
+    #[test]
+    fn mixed_strategies() {
+        let mut a = crate::crdt_snapshot::Crdt::new(1);
+        let mut b = crate::crdt_undo::Crdt::new(2);
+        let root = a.new_node(None).unwrap();
+        let x = a.new_node(Some(root)).unwrap();
+        let y = a.new_node(Some(root)).unwrap();
+        b.merge(&a).unwrap();
+
+        b.mov(x, Some(y)).unwrap();
+        b.mov_to(y, Some(root), 0).unwrap();
+        a.mov(y, Some(x)).unwrap();
+        a.delete(root).unwrap();
+        let z = b.new_node(Some(root)).unwrap();
+        a.merge(&b).unwrap();
+        b.merge(&a).unwrap();
+        assert_eq!(a.version(), b.version());
+        for parent in [None, Some(root), Some(x), Some(y), Some(z)] {
+            assert_eq!(a.children_ordered(parent), b.children_ordered(parent));
+        }
+        for node in [root, x, y, z] {
+            assert_eq!(a.forest().node_state(node), b.forest().node_state(node));
+        }
+
+        // both strategies rebuild the same history
+        let (old_a, old_b) = (a.checkout(y), b.checkout(y));
+        assert_eq!(old_a.len(), 3);
+        for node in [root, x, y] {
+            assert_eq!(old_a.node_state(node), old_b.node_state(node));
+        }
+    }
+
+    #[test]
+    fn invalid_edits() {
+        let mut a = crate::crdt_undo::Crdt::new(1);
+        let root = a.new_node(None).unwrap();
+        let unknown = ID::new(5, 2);
+        assert_eq!(
+            a.new_node(Some(unknown)),
+            Err(EditError::ParentNotFound(unknown))
+        );
+        assert_eq!(
+            a.mov(unknown, Some(root)),
+            Err(EditError::NodeNotFound(unknown))
+        );
+        assert_eq!(
+            a.mov_to(root, Some(unknown), 0),
+            Err(EditError::ParentNotFound(unknown))
+        );
+        assert_eq!(a.delete(unknown), Err(EditError::NodeNotFound(unknown)));
+        assert_eq!(a.undelete(unknown), Err(EditError::NodeNotFound(unknown)));
+        // nothing is recorded, so the peers can still import the ops of this replica
+        assert_eq!(a.version().get(1), 1);
+        let mut b = crate::crdt_snapshot::Crdt::new(2);
+        b.merge(&a).unwrap();
+
+        // the lamport of a remote op is the largest one that can be imported
+        let last = ID::new(Lamport::MAX - 1, 3);
+        a.import_ops([Op::new(last, 0, OpContent::Delete(root))])
+            .unwrap();
+        assert_eq!(a.mov(root, None), Err(EditError::LamportOverflow));
+        assert_eq!(a.version().get(1), 1);
+        let id = a.export_ops(&b.version()).unwrap()[0].id();
+        assert_eq!(a.revert_op(id), Err(RevertError::LamportOverflow));
+    }
+
+    fn merge_moves<B: TreeBackend, S: Strategy<B>>() {
+        let mut a = Crdt::<B, S>::new(1);
+        let mut b = Crdt::<B, S>::new(2);
+        let mut ids = Vec::new();
+        for _ in 0..10 {
+            ids.push(a.new_node(None).unwrap());
+        }
+
+        a.mov(ids[0], Some(ids[2])).unwrap();
+        b.merge(&a).unwrap();
+        b.mov(ids[3], Some(ids[1])).unwrap();
+        a.merge(&b).unwrap();
+        assert_eq!(a.forest(), b.forest());
+
+        // moving a node into itself changes nothing
+        a.delete(ids[0]).unwrap();
+        a.mov(ids[0], Some(ids[0])).unwrap();
+        b.mov(ids[1], Some(ids[1])).unwrap();
+        b.merge(&a).unwrap();
+        a.merge(&b).unwrap();
+        assert_eq!(a.forest(), b.forest());
+    }
+
+    fn repeated_moves<B: TreeBackend, S: Strategy<B>>() {
+        let mut a = Crdt::<B, S>::new(1);
+        let mut ids = Vec::new();
+        for _ in 0..10 {
+            ids.push(a.new_node(None).unwrap());
+        }
+
+        for i in 0..1_000 {
+            a.mov(ids[i % 10], ids[(i + 1) % 10].into()).unwrap();
+        }
+        let mut b = Crdt::<B, S>::new(2);
+        b.merge(&a).unwrap();
+        assert_eq!(a.forest(), b.forest());
+    }
+
+    fn concurrent_siblings<B: TreeBackend, S: Strategy<B>>() {
+        let mut a = Crdt::<B, S>::new(1);
+        let mut b = Crdt::<B, S>::new(2);
+        let root = a.new_node(None).unwrap();
+        let first = a.new_node(Some(root)).unwrap();
+        let last = a.new_node(Some(root)).unwrap();
+        b.merge(&a).unwrap();
+
+        // insert at the same spot concurrently
+        let x = a.new_node_at(Some(root), 1).unwrap();
+        let y = b.new_node_at(Some(root), 1).unwrap();
+        b.mov_to(first, Some(root), 1).unwrap();
+        a.merge(&b).unwrap();
+        b.merge(&a).unwrap();
+        assert_eq!(a.forest(), b.forest());
+        let children = a.children_ordered(Some(root));
+        assert_eq!(children, b.children_ordered(Some(root)));
+        assert_eq!(children.len(), 4);
+        assert_eq!(children[3], last);
+        assert!(children.contains(&x));
+        let pos = |id| children.iter().position(|x| *x == id).unwrap();
+        assert!(pos(y) < pos(first));
+
+        // insert between the two concurrent inserts
+        let z = a.new_node_at(Some(root), 1).unwrap();
+        b.merge(&a).unwrap();
+        assert_eq!(a.children_ordered(Some(root))[1], z);
+        assert_eq!(b.children_ordered(Some(root))[1], z);
+    }
+
+    fn export_import<B: TreeBackend, S: Strategy<B>>() {
+        let mut a = Crdt::<B, S>::new(1);
+        let mut b = Crdt::<B, S>::new(2);
+        let root = a.new_node(None).unwrap();
+        let child = a.new_node(Some(root)).unwrap();
+        b.import_ops(a.export_ops(&b.version()).unwrap()).unwrap();
+        assert_eq!(b.version().get(1), 2);
+
+        let since = a.version();
+        b.mov(child, None).unwrap();
+        let other = b.new_node(Some(root)).unwrap();
+        a.delete(root).unwrap();
+        let ops = b.export_ops(&since).unwrap();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].id().client(), 2);
+        assert_eq!(ops[1].counter(), 1);
+        assert!(matches!(ops[1].content(), OpContent::New { parent, .. } if *parent == Some(root)));
+
+        // duplicated and reordered ops are fine
+        let mut ops: Vec<Op> = decode_ops(&encode_ops(&ops)).unwrap();
+        ops.reverse();
+        ops.extend(b.export_ops(&Default::default()).unwrap());
+        a.import_ops(ops).unwrap();
+        b.import_ops(a.export_ops(&b.version()).unwrap()).unwrap();
+        assert_eq!(a.version(), b.version());
+        assert_eq!(a.forest(), b.forest());
+        assert_eq!(a.forest().parent_of(other), Some(root));
+
+        // an op waits until its predecessor arrives
+        let mut c = Crdt::<B, S>::new(3);
+        let ops = a.export_ops(&Default::default()).unwrap();
+        let (first, rest): (Vec<Op>, Vec<Op>) = ops.into_iter().partition(|op| op.counter() == 0);
+        c.import_ops(rest).unwrap();
+        assert_eq!(c.version().get(1), 0);
+        assert_eq!(c.version().get(2), 0);
+        assert_eq!(c.pending_len(), 3);
+        c.import_ops(first).unwrap();
+        assert_eq!(c.pending_len(), 0);
+        assert_eq!(c.forest(), a.forest());
+    }
+
+    #[cfg(feature = "serde")]
+    fn serde<B, S>()
+    where
+        B: TreeBackend + ::serde::Serialize + ::serde::de::DeserializeOwned,
+        S: Strategy<B>,
+    {
+        let mut a = Crdt::<B, S>::new(1);
+        let mut b = Crdt::<B, S>::new(2);
+        let root = a.new_node(None).unwrap();
+        a.new_node(Some(root)).unwrap();
+        b.merge(&a).unwrap();
+        b.delete(root).unwrap();
+        a.mov(root, None).unwrap();
+        a.merge(&b).unwrap();
+
+        let json = serde_json::to_string(&a).unwrap();
+        let c: Crdt<B, S> = serde_json::from_str(&json).unwrap();
+        assert_eq!(c.forest(), a.forest());
+        assert_eq!(c.version(), a.version());
+        let ops = a.export_ops(&Default::default()).unwrap();
+        let bytes = rmp_serde::to_vec(&ops).unwrap();
+        let decoded: Vec<Op> = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(encode_ops(&decoded), encode_ops(&ops));
+
+        // the ops waiting for their dependencies are kept
+        let gap: Vec<&Op> = ops.iter().filter(|op| op.counter() != 0).collect();
+        let json = format!(
+            r#"{{"client":3,"ops":{}}}"#,
+            serde_json::to_string(&gap).unwrap()
+        );
+        let c: Crdt<B, S> = serde_json::from_str(&json).unwrap();
+        assert_eq!(c.pending_len(), gap.len());
+        assert_eq!(serde_json::to_string(&c).unwrap(), json);
+        let duplicated = serde_json::to_string(&[&ops[0], &ops[0]]).unwrap();
+        let json = format!(r#"{{"client":3,"ops":{}}}"#, duplicated);
+        assert!(serde_json::from_str::<Crdt<B, S>>(&json).is_err());
+
+        // the lamport of the baseline is too large to count the next ops from
+        let mut d = Crdt::<B, S>::new(4);
+        d.new_node(None).unwrap();
+        d.compact(&d.version());
+        let json = serde_json::to_string(&d).unwrap();
+        let c: Crdt<B, S> = serde_json::from_str(&serde_json::to_string(&d).unwrap()).unwrap();
+        assert_eq!(c.forest(), d.forest());
+        assert_eq!(c.baseline_version(), &d.version());
+        let json = json.replace(r#""last":{"lamport":0"#, r#""last":{"lamport":4294967295"#);
+        assert!(serde_json::from_str::<Crdt<B, S>>(&json).is_err());
+    }
+
+    fn out_of_order<B: TreeBackend, S: Strategy<B>>() {
+        use rand::{seq::SliceRandom, Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut replicas: Vec<Crdt<B, S>> = (0..3).map(Crdt::<B, S>::new).collect();
+        let mut nodes = Vec::new();
+        for _ in 0..300 {
+            let i = rng.gen_range(0..3);
+            let r = &mut replicas[i];
+            match rng.gen_range(0..4) {
+                0 => {
+                    let parent = nodes.choose(&mut rng).copied();
+                    if parent.map(|p| r.forest().contains(p)).unwrap_or(true) {
+                        nodes.push(r.new_node(parent).unwrap());
+                    }
+                }
+                1 | 2 => {
+                    let (Some(&a), Some(&b)) = (nodes.choose(&mut rng), nodes.choose(&mut rng))
+                    else {
+                        continue;
+                    };
+                    if r.forest().contains(a) && r.forest().contains(b) {
+                        r.mov(a, Some(b)).unwrap();
+                    }
+                }
+                _ => {
+                    let j = rng.gen_range(0..3);
+                    let other = replicas[j].clone();
+                    replicas[i].merge(&other).unwrap();
+                }
+            }
+        }
+
+        let mut merged = Crdt::<B, S>::new(10);
+        for r in replicas.iter() {
+            merged.merge(r).unwrap();
+        }
+        let mut ops: Vec<Op> = replicas
+            .iter()
+            .flat_map(|r| r.export_ops(&Default::default()).unwrap())
+            .collect();
+        ops.shuffle(&mut rng);
+        let mut c = Crdt::<B, S>::new(11);
+        for chunk in ops.chunks(7) {
+            c.import_ops(chunk.to_vec()).unwrap();
+        }
+        assert_eq!(c.pending_len(), 0);
+        assert_eq!(c.version(), merged.version());
+        assert_eq!(c.forest(), merged.forest());
+    }
+
+    fn checkout<B: TreeBackend, S: Strategy<B>>() {
+        let mut a = Crdt::<B, S>::new(1);
+        let mut b = Crdt::<B, S>::new(2);
+        let root = a.new_node(None).unwrap();
+        let child = a.new_node(Some(root)).unwrap();
+        b.merge(&a).unwrap();
+        let yesterday = a.version();
+        let forest_yesterday = a.forest().clone();
+        for _ in 0..100 {
+            a.mov(child, None).unwrap();
+            a.mov(child, Some(root)).unwrap();
+        }
+        b.delete(child).unwrap();
+        let other = b.new_node(None).unwrap();
+        a.merge(&b).unwrap();
+
+        let latest = a.forest().clone();
+        assert_eq!(a.checkout_version(&yesterday), forest_yesterday);
+        assert_eq!(a.checkout(child), forest_yesterday);
+        assert_eq!(a.checkout_version(&a.version()), latest);
+        assert!(a.checkout(ID::new(0, 0)).is_empty());
+        // only the ops of b after yesterday
+        let mut only_b = yesterday.clone();
+        only_b.set(2, b.version().get(2));
+        let forest = a.checkout_version(&only_b);
+        assert!(forest.is_deleted(child));
+        assert!(forest.contains(other));
+        assert_eq!(forest.parent_of(child), Some(root));
+        // the live state is not changed
+        assert_eq!(a.forest(), &latest);
+
+        // the same as replaying from scratch
+        let ops = a.export_ops(&Default::default()).unwrap();
+        for lamport in (0..200).step_by(17) {
+            let id = ID::new(lamport, 1);
+            let mut fresh = Crdt::<B, S>::new(3);
+            fresh
+                .import_ops(ops.iter().filter(|op| op.id() <= id).cloned())
+                .unwrap();
+            assert_eq!(&a.checkout(id), fresh.forest());
+        }
+    }
+
+    fn revert_op<B: TreeBackend, S: Strategy<B>>() {
+        let mut a = Crdt::<B, S>::new(1);
+        let mut b = Crdt::<B, S>::new(2);
+        let last_op = |c: &Crdt<B, S>| {
+            let mut version = c.version();
+            version.set(1, version.get(1) - 1);
+            c.export_ops(&version).unwrap()[0].id()
+        };
+        let x = a.new_node(None).unwrap();
+        let y = a.new_node(None).unwrap();
+        let z = a.new_node(Some(x)).unwrap();
+        let new_z = last_op(&a);
+        a.mov(z, Some(y)).unwrap();
+        let mov_z = last_op(&a);
+        a.revert_op(mov_z).unwrap();
+        assert_eq!(a.forest().parent_of(z), Some(x));
+        assert_eq!(a.revert_op(mov_z), Err(RevertError::NothingToRevert));
+        assert_eq!(
+            a.revert_op(ID::new(100, 1)),
+            Err(RevertError::OpNotFound(ID::new(100, 1)))
+        );
+
+        a.delete(y).unwrap();
+        let delete_y = last_op(&a);
+        a.revert_op(delete_y).unwrap();
+        assert!(!a.forest().is_deleted(y));
+        a.revert_op(new_z).unwrap();
+        assert!(a.forest().is_deleted(z));
+        b.merge(&a).unwrap();
+        assert_eq!(a.forest(), b.forest());
+
+        // the old parent is deleted
+        a.mov(z, Some(y)).unwrap();
+        let mov_z = last_op(&a);
+        a.delete(x).unwrap();
+        assert_eq!(a.revert_op(mov_z), Err(RevertError::ParentDeleted(x)));
+
+        // the old parent is now a descendant
+        a.undelete(x).unwrap();
+        a.mov(x, Some(z)).unwrap();
+        assert_eq!(a.revert_op(mov_z), Err(RevertError::CyclicMove));
+    }
+
+    fn compact<B: TreeBackend, S: Strategy<B>>() {
+        let mut a = Crdt::<B, S>::new(1);
+        let mut b = Crdt::<B, S>::new(2);
+        let root = a.new_node(None).unwrap();
+        let child = a.new_node(Some(root)).unwrap();
+        b.merge(&a).unwrap();
+        b.mov(child, None).unwrap();
+        a.merge(&b).unwrap();
+        let stable = b.version();
+        let forest = a.forest().clone();
+        assert_eq!(a.compact(&stable), 3);
+        assert_eq!(a.compact(&stable), 0);
+        assert_eq!(a.forest(), &forest);
+        assert_eq!(a.baseline_version(), &stable);
+        assert_eq!(a.version(), stable);
+        assert_eq!(a.revert_op(root), Err(RevertError::OpNotFound(root)));
+
+        // the versions before the baseline can't be rebuilt
+        assert_eq!(a.checkout(ID::new(0, 1)), forest);
+
+        // the ops newer than the baseline are merged as usual
+        a.mov(child, Some(root)).unwrap();
+        b.delete(root).unwrap();
+        let x = b.new_node(Some(child)).unwrap();
+        a.merge(&b).unwrap();
+        b.merge(&a).unwrap();
+        assert_eq!(a.forest(), b.forest());
+        assert_eq!(a.forest().parent_of(x), Some(child));
+        assert_eq!(a.version(), b.version());
+
+        // a peer behind the baseline can't catch up from the ops
+        let mut late = Crdt::<B, S>::new(3);
+        assert!(matches!(late.merge(&a), Err(SyncError::BehindBaseline(_))));
+        late.merge(&b).unwrap();
+        assert_eq!(late.forest(), a.forest());
+
+        // an op sorting before the baseline is rejected, and nothing is imported
+        let mut c = Crdt::<B, S>::new(4);
+        let y = c.new_node(None).unwrap();
+        c.mov(y, None).unwrap();
+        let version = a.version();
+        assert_eq!(a.merge(&c), Err(SyncError::OpBeforeBaseline(y)));
+        assert_eq!(a.version(), version);
+        assert_eq!(a.pending_len(), 0);
+    }
+
+    fn changes<B: TreeBackend, S: Strategy<B>>() {
+        let mut a = Crdt::<B, S>::new(1);
+        let mut b = Crdt::<B, S>::new(2);
+        let root = a.new_node(None).unwrap();
+        let child = a.new_node(Some(root)).unwrap();
+        assert_eq!(
+            b.merge(&a).unwrap(),
+            vec![
+                Change::Created {
+                    node: root,
+                    parent: None
+                },
+                Change::Created {
+                    node: child,
+                    parent: Some(root)
+                },
+            ]
+        );
+        assert!(b.merge(&a).unwrap().is_empty());
+
+        let moved = |from, to| Change::Moved {
+            node: child,
+            from,
+            to,
+        };
+        assert_eq!(a.mov(child, None).unwrap(), vec![moved(Some(root), None)]);
+        assert_eq!(
+            a.mov(child, Some(root)).unwrap(),
+            vec![moved(None, Some(root))]
+        );
+        // a cyclic move changes nothing
+        assert!(a.mov(root, Some(child)).unwrap().is_empty());
+        assert_eq!(a.delete(child).unwrap(), vec![Change::Deleted(child)]);
+        assert!(a.delete(child).unwrap().is_empty());
+        assert_eq!(a.undelete(child).unwrap(), vec![Change::Restored(child)]);
+
+        // the ops of b sort before the ops of a, so the moves of a are reverted and applied
+        // again while merging. Only the net changes are reported
+        let other = b.new_node(None).unwrap();
+        b.mov(other, Some(root)).unwrap();
+        assert_eq!(
+            a.merge(&b).unwrap(),
+            vec![Change::Created {
+                node: other,
+                parent: Some(root)
+            }]
+        );
+        b.merge(&a).unwrap();
+        assert_eq!(a.forest(), b.forest());
+    }
+
+    fn validation<B: TreeBackend, S: Strategy<B>>() {
+        let mut a = Crdt::<B, S>::new(1);
+        let root = a.new_node(None).unwrap();
+        let child = a.new_node(Some(root)).unwrap();
+        a.mov(child, None).unwrap();
+        let mut b = Crdt::<B, S>::new(2);
+        b.merge(&a).unwrap();
+        let version = b.version();
+        let forest = b.forest().clone();
+        let mut reject = |ops: Vec<Op>, err: SyncError| {
+            assert_eq!(b.import_ops(ops), Err(err));
+            assert_eq!(b.version(), version);
+            assert_eq!(b.forest(), &forest);
+            assert_eq!(b.pending_len(), 0);
+        };
+        let new = |parent| OpContent::New {
+            parent,
+            position: FractionalIndex::default(),
+        };
+        let x = ID::new(10, 3);
+        let valid = Op::new(x, 0, new(Some(child)));
+
+        // the op at lamport 2 is a move
+        let unknown = ID::new(2, 1);
+        reject(
+            vec![
+                valid.clone(),
+                Op::new(ID::new(11, 3), 1, OpContent::Delete(unknown)),
+            ],
+            SyncError::UnknownReference {
+                op: ID::new(11, 3),
+                node: unknown,
+            },
+        );
+        // a node can't be referred to before it's created
+        reject(
+            vec![Op::new(x, 0, new(Some(x)))],
+            SyncError::UnknownReference { op: x, node: x },
+        );
+        reject(
+            vec![Op::new(root, 0, new(Some(child)))],
+            SyncError::DuplicateId(root),
+        );
+        reject(
+            vec![valid.clone(), Op::new(x, 1, new(None))],
+            SyncError::DuplicateId(x),
+        );
+        reject(
+            vec![Op::new(ID::new(5, 1), 0, new(None))],
+            SyncError::InvalidSequence(ID::new(5, 1)),
+        );
+        // 5 ops of a client can't fit in 2 lamports
+        reject(
+            vec![valid.clone(), Op::new(ID::new(12, 3), 5, new(None))],
+            SyncError::InvalidSequence(x),
+        );
+        let max = ID::new(Lamport::MAX, 3);
+        reject(
+            vec![Op::new(max, 0, new(None))],
+            SyncError::LamportOverflow(max),
+        );
+        b.set_max_batch_len(2);
+        let batch: Vec<Op> = (0..3)
+            .map(|i| Op::new(ID::new(10 + i, 3), i, new(None)))
+            .collect();
+        assert_eq!(
+            b.import_ops(batch.clone()),
+            Err(SyncError::BatchTooLarge { len: 3, max: 2 })
+        );
+        b.set_max_batch_len(DEFAULT_MAX_BATCH_LEN);
+        b.import_ops(batch).unwrap();
+
+        // a node created by an op that has not arrived yet is fine
+        let later = Op::new(ID::new(13, 3), 3, OpContent::Delete(ID::new(3, 5)));
+        b.import_ops([later]).unwrap();
+        assert_eq!(b.pending_len(), 1);
+    }
+}
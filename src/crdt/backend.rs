@@ -0,0 +1,190 @@
+use std::fmt::Debug;
+
+use super::ID;
+use crate::{fractional_index::FractionalIndex, mut_tree};
+
+/// The state of a node that an op may change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeState {
+    pub parent: Option<ID>,
+    pub position: FractionalIndex,
+    pub deleted: bool,
+}
+
+/// A forest the ops of [`super::Crdt`] can be applied to.
+///
+/// Every node carries its position among the siblings, and the children are kept in the
+/// order of the positions by the CRDT.
+pub trait TreeBackend: Debug + Clone + Default + PartialEq {
+    fn contains(&self, node: ID) -> bool;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn parent_of(&self, node: ID) -> Option<ID>;
+    /// Whether the node is deleted. A node that doesn't exist is not deleted.
+    fn is_deleted(&self, node: ID) -> bool;
+    fn position(&self, node: ID) -> Option<&FractionalIndex>;
+    fn children_len(&self, parent: Option<ID>) -> usize;
+    fn child_at(&self, parent: Option<ID>, index: usize) -> Option<ID>;
+    fn index_in_parent(&self, node: ID) -> Option<usize>;
+
+    /// Iterate over the children of parent in order.
+    ///
+    /// The default looks up every child by [`TreeBackend::child_at`].
+    fn children_of(&self, parent: Option<ID>) -> Box<dyn Iterator<Item = ID> + '_> {
+        Box::new((0..self.children_len(parent)).filter_map(move |i| self.child_at(parent, i)))
+    }
+
+    /// The number of children of parent that sort before `(position, node)`
+    fn children_before(&self, parent: Option<ID>, position: &FractionalIndex, node: ID) -> usize {
+        let mut start = 0;
+        let mut end = self.children_len(parent);
+        while start < end {
+            let mid = (start + end) / 2;
+            let sibling = self.child_at(parent, mid).unwrap();
+            if (self.position(sibling).unwrap(), sibling) < (position, node) {
+                start = mid + 1;
+            } else {
+                end = mid;
+            }
+        }
+        start
+    }
+
+    /// Move the node to the `index`-th child of parent, creating it if it doesn't exist.
+    /// Return false and change nothing if the move would cause a cycle.
+    fn move_node(&mut self, node: ID, parent: Option<ID>, index: usize) -> bool;
+    /// Set the position of an existing node. The siblings are not reordered.
+    fn set_position(&mut self, node: ID, position: FractionalIndex);
+    /// Delete or restore an existing node
+    fn set_deleted(&mut self, node: ID, deleted: bool);
+
+    fn node_state(&self, node: ID) -> Option<NodeState> {
+        Some(NodeState {
+            parent: self.parent_of(node),
+            position: self.position(node)?.clone(),
+            deleted: self.is_deleted(node),
+        })
+    }
+}
+
+impl TreeBackend for crate::Forest<ID, FractionalIndex> {
+    fn contains(&self, node: ID) -> bool {
+        self.contains(node)
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn parent_of(&self, node: ID) -> Option<ID> {
+        self.parent_of(node)
+    }
+
+    fn is_deleted(&self, node: ID) -> bool {
+        self.is_deleted(node)
+    }
+
+    fn position(&self, node: ID) -> Option<&FractionalIndex> {
+        self.get_value(node)
+    }
+
+    fn children_len(&self, parent: Option<ID>) -> usize {
+        self.children_len(parent)
+    }
+
+    fn child_at(&self, parent: Option<ID>, index: usize) -> Option<ID> {
+        self.child_at(parent, index)
+    }
+
+    fn index_in_parent(&self, node: ID) -> Option<usize> {
+        self.index_in_parent(node)
+    }
+
+    fn children_of(&self, parent: Option<ID>) -> Box<dyn Iterator<Item = ID> + '_> {
+        Box::new(self.children_of(parent))
+    }
+
+    fn children_before(&self, parent: Option<ID>, position: &FractionalIndex, node: ID) -> usize {
+        self.children_partition_point(parent, |x| {
+            (self.get_value(x).unwrap(), x) < (position, node)
+        })
+    }
+
+    fn move_node(&mut self, node: ID, parent: Option<ID>, index: usize) -> bool {
+        self.mov_to(node, parent, index).is_ok()
+    }
+
+    fn set_position(&mut self, node: ID, position: FractionalIndex) {
+        self.set_value(node, position).ok();
+    }
+
+    fn set_deleted(&mut self, node: ID, deleted: bool) {
+        if deleted {
+            self.delete(node).unwrap_or_default();
+        } else {
+            self.undo_delete(node).unwrap_or_default();
+        }
+    }
+}
+
+impl TreeBackend for mut_tree::Forest<ID, FractionalIndex> {
+    fn contains(&self, node: ID) -> bool {
+        self.contains(node)
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn parent_of(&self, node: ID) -> Option<ID> {
+        self.parent_of(node)
+    }
+
+    fn is_deleted(&self, node: ID) -> bool {
+        self.is_deleted(node)
+    }
+
+    fn position(&self, node: ID) -> Option<&FractionalIndex> {
+        self.get_value(node)
+    }
+
+    fn children_len(&self, parent: Option<ID>) -> usize {
+        self.children_len(parent)
+    }
+
+    fn child_at(&self, parent: Option<ID>, index: usize) -> Option<ID> {
+        self.child_at(parent, index)
+    }
+
+    fn index_in_parent(&self, node: ID) -> Option<usize> {
+        self.index_in_parent(node)
+    }
+
+    fn children_of(&self, parent: Option<ID>) -> Box<dyn Iterator<Item = ID> + '_> {
+        Box::new(self.children_of(parent))
+    }
+
+    fn children_before(&self, parent: Option<ID>, position: &FractionalIndex, node: ID) -> usize {
+        self.children_partition_point(parent, |x| {
+            (self.get_value(x).unwrap(), x) < (position, node)
+        })
+    }
+
+    fn move_node(&mut self, node: ID, parent: Option<ID>, index: usize) -> bool {
+        self.mov_to(node, parent, index).is_ok()
+    }
+
+    fn set_position(&mut self, node: ID, position: FractionalIndex) {
+        self.set_value(node, position).ok();
+    }
+
+    fn set_deleted(&mut self, node: ID, deleted: bool) {
+        if deleted {
+            self.delete(node).unwrap_or_default();
+        } else {
+            self.undo_delete(node).unwrap_or_default();
+        }
+    }
+}
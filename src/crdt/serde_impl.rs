@@ -0,0 +1,82 @@
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{Client, Crdt, Op, Strategy, SyncError, TreeBackend, DEFAULT_MAX_BATCH_LEN, ID};
+use crate::version_vector::VersionVector;
+
+/// A replica is serialized as its baseline and op log, and rebuilt by importing the ops.
+#[derive(Serialize)]
+struct CrdtRef<'a, B> {
+    client: Client,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    baseline: Option<BaselineRef<'a, B>>,
+    ops: Vec<&'a Op>,
+}
+
+#[derive(Serialize)]
+struct BaselineRef<'a, B> {
+    forest: &'a B,
+    version: &'a VersionVector,
+    last: ID,
+}
+
+#[derive(Deserialize)]
+struct CrdtState<B> {
+    client: Client,
+    #[serde(default)]
+    baseline: Option<Baseline<B>>,
+    ops: Vec<Op>,
+}
+
+#[derive(Deserialize)]
+struct Baseline<B> {
+    forest: B,
+    version: VersionVector,
+    last: ID,
+}
+
+impl<B: TreeBackend + Serialize, S> Serialize for Crdt<B, S> {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        CrdtRef {
+            client: self.client,
+            baseline: self.baseline_last.map(|last| BaselineRef {
+                forest: &self.baseline,
+                version: &self.baseline_version,
+                last,
+            }),
+            ops: self
+                .log
+                .values()
+                .flatten()
+                .chain(self.pending.values())
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, B: TreeBackend + Deserialize<'de>, S: Strategy<B>> Deserialize<'de> for Crdt<B, S> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = CrdtState::<B>::deserialize(deserializer)?;
+        let len = state.ops.len();
+        let mut crdt = Crdt::new(state.client);
+        if let Some(baseline) = state.baseline {
+            crdt.forest = baseline.forest.clone();
+            crdt.baseline = baseline.forest;
+            crdt.baseline_version = baseline.version;
+            crdt.baseline_last = Some(baseline.last);
+            crdt.next_lamport = baseline
+                .last
+                .lamport
+                .checked_add(1)
+                .ok_or_else(|| D::Error::custom(SyncError::LamportOverflow(baseline.last)))?;
+        }
+        // the whole state is in memory already, so the batch is not limited
+        crdt.max_batch_len = usize::MAX;
+        crdt.import_ops(state.ops).map_err(D::Error::custom)?;
+        crdt.max_batch_len = DEFAULT_MAX_BATCH_LEN;
+        if crdt.log.values().map(|x| x.len()).sum::<usize>() + crdt.pending_len() != len {
+            return Err(D::Error::custom("the ops are duplicated"));
+        }
+        Ok(crdt)
+    }
+}
@@ -0,0 +1,401 @@
+use std::fmt::Display;
+
+use fxhash::FxHashMap;
+
+use super::{
+    Crdt, NodeState, Op, OpContent, Strategy, SyncError, TreeBackend, DEFAULT_MAX_BATCH_LEN, ID,
+};
+use crate::{
+    encoding::{self, DecodeError, RawBaseline, RawNode, RawOp, RawSnapshot},
+    fractional_index::FractionalIndex,
+    version_vector::VersionVector,
+};
+
+/// The reason why [`Crdt::import_snapshot`] can't restore a replica
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    Decode(DecodeError),
+    /// The ops in the snapshot can't be imported
+    Sync(SyncError),
+    /// A node appears twice, the siblings are not sorted by `(position, id)`, or a forest
+    /// ends in the middle of the children of a node
+    InvalidForest,
+    /// The version or the pending ops don't match the op log, or the forest doesn't match
+    /// the ops applied to the baseline
+    Inconsistent,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Decode(err) => write!(f, "invalid encoding: {}", err),
+            SnapshotError::Sync(err) => write!(f, "invalid ops: {}", err),
+            SnapshotError::InvalidForest => write!(f, "invalid forest"),
+            SnapshotError::Inconsistent => write!(f, "the version doesn't match the ops"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<DecodeError> for SnapshotError {
+    fn from(err: DecodeError) -> Self {
+        SnapshotError::Decode(err)
+    }
+}
+
+impl From<SyncError> for SnapshotError {
+    fn from(err: SyncError) -> Self {
+        SnapshotError::Sync(err)
+    }
+}
+
+impl<B: TreeBackend, S: Strategy<B>> Crdt<B, S> {
+    /// Encode the forest, the op log and the version of the replica with the format
+    /// described in [`crate::encoding`].
+    ///
+    /// Unlike the ops alone, the snapshot can be restored without applying the ops.
+    pub fn export_snapshot(&self) -> Vec<u8> {
+        let raw_id = |id: ID| (id.lamport, id.client);
+        let mut version: Vec<(u64, u32)> = self.version().iter().collect();
+        version.sort_unstable();
+        let mut clients: Vec<_> = self.log.keys().copied().collect();
+        clients.sort_unstable();
+        encoding::encode_snapshot(&RawSnapshot {
+            client: self.client,
+            baseline: self.baseline_last.map(|last| {
+                let mut version: Vec<(u64, u32)> = self.baseline_version.iter().collect();
+                version.sort_unstable();
+                RawBaseline {
+                    last: raw_id(last),
+                    version,
+                    forest: raw_nodes(&self.baseline),
+                }
+            }),
+            version,
+            forest: raw_nodes(&self.forest),
+            ops: clients
+                .iter()
+                .flat_map(|client| &self.log[client])
+                .map(RawOp::from)
+                .collect(),
+            pending: self.pending.values().map(RawOp::from).collect(),
+        })
+    }
+
+    /// Restore a replica from a snapshot exported by [`Crdt::export_snapshot`].
+    ///
+    /// The forest is restored as it is, and the ops are only checked and sorted. The history
+    /// kept by the strategy, e.g. the snapshots of [`super::Snapshots`], is rebuilt when a
+    /// merged op sorts before the restored ops.
+    pub fn import_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let raw = encoding::decode_snapshot(bytes)?;
+        let id = |(lamport, client)| ID { lamport, client };
+        let mut crdt = Crdt::new(raw.client);
+        if let Some(baseline) = raw.baseline {
+            let last = id(baseline.last);
+            crdt.baseline = build_forest(baseline.forest, |_, _| true)?;
+            crdt.baseline_version = to_version(&baseline.version);
+            crdt.baseline_last = Some(last);
+            crdt.next_lamport = last
+                .lamport
+                .checked_add(1)
+                .ok_or(SyncError::LamportOverflow(last))?;
+        }
+
+        let (log_len, pending_len) = (raw.ops.len(), raw.pending.len());
+        let ops = raw.ops.into_iter().chain(raw.pending).map(Op::from);
+        crdt.max_batch_len = usize::MAX;
+        let batch = crdt.validate(ops)?;
+        crdt.max_batch_len = DEFAULT_MAX_BATCH_LEN;
+        crdt.pending.extend(batch);
+        crdt.sorted_ops = crdt.release_pending();
+        crdt.applied_end = crdt.sorted_ops.len();
+        if crdt.sorted_ops.len() != log_len
+            || crdt.pending.len() != pending_len
+            || crdt.version() != to_version(&raw.version)
+        {
+            return Err(SnapshotError::Inconsistent);
+        }
+
+        let states = OpStates::new(&crdt.sorted_ops);
+        crdt.forest = build_forest(raw.forest, |id, state| {
+            states.accepts(&crdt.baseline, id, state)
+        })?;
+        Ok(crdt)
+    }
+}
+
+fn to_version(raw: &[(u64, u32)]) -> VersionVector {
+    let mut version = VersionVector::new();
+    for &(client, len) in raw {
+        version.set(client, len);
+    }
+    version
+}
+
+/// The nodes of the forest in preorder
+fn raw_nodes<B: TreeBackend>(forest: &B) -> Vec<RawNode> {
+    let mut ans = Vec::with_capacity(forest.len());
+    // the children left to visit at each level
+    let mut stack = vec![forest.children_of(None)];
+    while let Some(children) = stack.last_mut() {
+        let Some(node) = children.next() else {
+            stack.pop();
+            continue;
+        };
+        ans.push(RawNode {
+            id: (node.lamport, node.client),
+            position: forest.position(node).unwrap().clone(),
+            deleted: forest.is_deleted(node),
+            children: forest.children_len(Some(node)),
+        });
+        stack.push(forest.children_of(Some(node)));
+    }
+    ans
+}
+
+/// The states the applied ops may have left the nodes in
+struct OpStates<'a> {
+    /// The parents and the positions given by the ops creating or moving a node
+    moves: FxHashMap<ID, Vec<(Option<ID>, &'a FractionalIndex)>>,
+    /// Whether the last op deleting or restoring a node deleted it
+    deleted: FxHashMap<ID, bool>,
+}
+
+impl<'a> OpStates<'a> {
+    fn new(ops: &'a [Op]) -> Self {
+        let mut moves: FxHashMap<_, Vec<_>> = FxHashMap::default();
+        let mut deleted = FxHashMap::default();
+        for op in ops {
+            match &op.content {
+                OpContent::New { parent, position }
+                | OpContent::Move {
+                    parent, position, ..
+                } => {
+                    moves
+                        .entry(op.target())
+                        .or_default()
+                        .push((*parent, position));
+                }
+                OpContent::Delete(target) => {
+                    deleted.insert(*target, true);
+                }
+                OpContent::Undelete(target) => {
+                    deleted.insert(*target, false);
+                }
+            }
+        }
+        Self { moves, deleted }
+    }
+
+    /// Whether the ops applied to the baseline can leave the node in the state.
+    ///
+    /// A move that would cause a cycle changes nothing, so the parent and the position may
+    /// come from any op moving the node, or from the baseline, rather than from the last op.
+    fn accepts<B: TreeBackend>(&self, baseline: &B, id: ID, state: &NodeState) -> bool {
+        let old = baseline.node_state(id);
+        let moved = self
+            .moves
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .any(|(parent, position)| *parent == state.parent && *position == &state.position);
+        let kept = old
+            .as_ref()
+            .is_some_and(|old| old.parent == state.parent && old.position == state.position);
+        let deleted = match self.deleted.get(&id) {
+            Some(deleted) => *deleted,
+            None => old.is_some_and(|old| old.deleted),
+        };
+        (moved || kept) && deleted == state.deleted
+    }
+}
+
+/// Build the forest from the nodes in preorder. `check` tells whether a node may be in
+/// the state, and the snapshot is inconsistent if it may not.
+fn build_forest<B: TreeBackend>(
+    nodes: Vec<RawNode>,
+    check: impl Fn(ID, &NodeState) -> bool,
+) -> Result<B, SnapshotError> {
+    let mut forest = B::default();
+    // the nodes whose children are being added, and the number of the children left
+    let mut stack: Vec<(ID, usize)> = Vec::new();
+    for node in nodes {
+        while let Some((_, 0)) = stack.last() {
+            stack.pop();
+        }
+        let parent = stack.last_mut().map(|(parent, left)| {
+            *left -= 1;
+            *parent
+        });
+        let id = ID {
+            lamport: node.id.0,
+            client: node.id.1,
+        };
+        let index = forest.children_len(parent);
+        let sorted = match index.checked_sub(1) {
+            Some(last) => {
+                let last = forest.child_at(parent, last).unwrap();
+                (forest.position(last).unwrap(), last) < (&node.position, id)
+            }
+            None => true,
+        };
+        if !sorted || forest.contains(id) || !forest.move_node(id, parent, index) {
+            return Err(SnapshotError::InvalidForest);
+        }
+        let state = NodeState {
+            parent,
+            position: node.position,
+            deleted: node.deleted,
+        };
+        if !check(id, &state) {
+            return Err(SnapshotError::Inconsistent);
+        }
+        forest.set_position(id, state.position);
+        forest.set_deleted(id, state.deleted);
+        stack.push((id, node.children));
+    }
+    if stack.iter().any(|(_, left)| *left > 0) {
+        return Err(SnapshotError::InvalidForest);
+    }
+    Ok(forest)
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::{crdt_snapshot, crdt_undo};
+
+    #[test]
+    fn round_trip() {
+        let mut a = crdt_undo::Crdt::new(1);
+        let mut b = crdt_undo::Crdt::new(2);
+        let root = a.new_node(None).unwrap();
+        let x = a.new_node(Some(root)).unwrap();
+        let y = a.new_node_at(Some(root), 0).unwrap();
+        b.merge(&a).unwrap();
+        b.mov(x, Some(y)).unwrap();
+        a.delete(y).unwrap();
+        a.merge(&b).unwrap();
+        let stable = a.version();
+        b.merge(&a).unwrap();
+        a.compact(&stable);
+        a.mov(x, None).unwrap();
+        let mov_x = a.export_ops(&stable).unwrap()[0].id();
+        // an op waiting for its predecessor
+        let mut c = crdt_undo::Crdt::new(3);
+        c.merge(&b).unwrap();
+        c.import_ops(a.export_ops(&stable).unwrap()).unwrap();
+        c.new_node(None).unwrap();
+        let z = c.new_node(None).unwrap();
+        let late = c.export_ops(&a.version()).unwrap();
+        a.import_ops(late.into_iter().filter(|op| op.id() == z))
+            .unwrap();
+        assert_eq!(a.pending_len(), 1);
+
+        let bytes = a.export_snapshot();
+        let mut restored = crdt_undo::Crdt::import_snapshot(&bytes).unwrap();
+        assert_eq!(restored.forest(), a.forest());
+        assert_eq!(restored.version(), a.version());
+        assert_eq!(restored.baseline_version(), a.baseline_version());
+        assert_eq!(restored.pending_len(), 1);
+        assert_eq!(
+            restored.children_ordered(Some(root)),
+            a.children_ordered(Some(root))
+        );
+        assert_eq!(restored.export_snapshot(), bytes);
+        // the state before a restored op is rebuilt from the baseline
+        let mut reverted = restored.clone();
+        reverted.undelete(y).unwrap();
+        reverted.revert_op(mov_x).unwrap();
+        assert_eq!(reverted.forest().parent_of(x), Some(y));
+
+        // the restored replica merges ops sorting before its ops as usual
+        b.mov(y, None).unwrap();
+        restored.merge(&b).unwrap();
+        restored.merge(&c).unwrap();
+        a.merge(&b).unwrap();
+        a.merge(&c).unwrap();
+        assert_eq!(restored.forest(), a.forest());
+        assert_eq!(restored.pending_len(), 0);
+        let id = restored.new_node(Some(x)).unwrap();
+        assert_eq!(id.client(), 1);
+        a.merge(&restored).unwrap();
+        assert_eq!(restored.forest(), a.forest());
+
+        // the snapshot is shared by both CRDTs
+        let mut other = crdt_snapshot::Crdt::import_snapshot(&a.export_snapshot()).unwrap();
+        assert_eq!(other.version(), a.version());
+        other.merge(&b).unwrap();
+        assert_eq!(other.children_ordered(Some(x)), a.children_ordered(Some(x)));
+    }
+
+    #[test]
+    fn invalid_snapshot() {
+        let mut a = crdt_snapshot::Crdt::new(1);
+        let root = a.new_node(None).unwrap();
+        a.new_node(Some(root)).unwrap();
+        a.new_node(Some(root)).unwrap();
+        let bytes = a.export_snapshot();
+        for i in 0..bytes.len() {
+            assert!(crdt_snapshot::Crdt::import_snapshot(&bytes[..i]).is_err());
+        }
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..10_000 {
+            let mut corrupted = bytes.clone();
+            let i = rng.gen_range(0..corrupted.len());
+            corrupted[i] = rng.gen();
+            let _ = crdt_snapshot::Crdt::import_snapshot(&corrupted);
+        }
+        // the baseline ends at the greatest lamport
+        let overflow = [
+            1, 1, 1, 1, 0xff, 0xff, 0xff, 0xff, 0x0f, 0, 0, 0, 0, 0, 0, 3, 1, 0, 0, 3, 1, 0, 0,
+        ];
+        assert_eq!(
+            crdt_snapshot::Crdt::import_snapshot(&overflow).unwrap_err(),
+            SnapshotError::Sync(SyncError::LamportOverflow(ID::new(u32::MAX, 1)))
+        );
+        let raw = |f: &dyn Fn(&mut RawSnapshot)| {
+            let mut raw = encoding::decode_snapshot(&bytes).unwrap();
+            f(&mut raw);
+            crdt_snapshot::Crdt::import_snapshot(&encoding::encode_snapshot(&raw)).err()
+        };
+        assert_eq!(raw(&|_| {}), None);
+        assert_eq!(
+            raw(&|x| x.forest[0].children = 3),
+            Some(SnapshotError::InvalidForest)
+        );
+        assert_eq!(
+            raw(&|x| x.forest[1].id = x.forest[0].id),
+            Some(SnapshotError::InvalidForest)
+        );
+        // the siblings are out of order
+        assert_eq!(
+            raw(&|x| x.forest.swap(1, 2)),
+            Some(SnapshotError::InvalidForest)
+        );
+        // the last node is under its sibling instead of the root
+        assert_eq!(
+            raw(&|x| {
+                x.forest[0].children = 1;
+                x.forest[1].children = 1;
+            }),
+            Some(SnapshotError::Inconsistent)
+        );
+        assert_eq!(
+            raw(&|x| x.forest[1].deleted = true),
+            Some(SnapshotError::Inconsistent)
+        );
+        assert_eq!(
+            raw(&|x| x.version.clear()),
+            Some(SnapshotError::Inconsistent)
+        );
+        assert_eq!(
+            raw(&|x| x.pending = x.ops.clone()),
+            Some(SnapshotError::Inconsistent)
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt::Debug;
+
+use super::{apply_op, mov_with_position, NodeState, Op, OpContent, TreeBackend, ID};
+use crate::log_spaced_snapshots::LogSpacedSnapshots;
+
+/// How [`super::Crdt`] brings the forest back to an older state, so that a remote op
+/// sorting before the applied ops can be applied in order.
+///
+/// `ops` are the applied ops sorted by id, and `forest` is the state after all of them.
+/// `baseline` is the state before the first of them.
+pub trait Strategy<B: TreeBackend>: Debug + Clone + Default {
+    /// Apply the op that sorts after all the applied ops to the live forest
+    fn apply(&mut self, forest: &mut B, op: &Op);
+
+    /// Bring the live forest back to the state after `ops[..k]` for some `k <= end`, and
+    /// return `k`. The records of `ops[k..]` are dropped, they are applied again later.
+    fn rewind(&mut self, forest: &mut B, baseline: &B, ops: &[Op], end: usize) -> usize;
+
+    /// The forest after `ops[..end]`. The live state is not changed.
+    fn state_at(&self, forest: &B, baseline: &B, ops: &[Op], end: usize) -> B;
+
+    /// The state of the node before `ops[i]`
+    fn node_before(
+        &self,
+        forest: &B,
+        baseline: &B,
+        ops: &[Op],
+        i: usize,
+        node: ID,
+    ) -> Option<NodeState> {
+        self.state_at(forest, baseline, ops, i).node_state(node)
+    }
+}
+
+/// Keep log-spaced snapshots of the forest, and replay the ops after the nearest one.
+///
+/// It suits a persistent backend like [`crate::Forest`], whose clone is O(1).
+#[derive(Debug, Clone)]
+pub struct Snapshots<B> {
+    cache: LogSpacedSnapshots<ID, B>,
+}
+
+impl<B> Default for Snapshots<B> {
+    fn default() -> Self {
+        Self {
+            cache: Default::default(),
+        }
+    }
+}
+
+impl<B> Snapshots<B> {
+    /// The number of snapshots kept
+    pub fn cache_size(&self) -> usize {
+        self.cache.cache_size()
+    }
+}
+
+impl<B: TreeBackend> Strategy<B> for Snapshots<B> {
+    fn apply(&mut self, forest: &mut B, op: &Op) {
+        apply_op(forest, op);
+        self.cache.push(op.id, forest.clone());
+    }
+
+    fn rewind(&mut self, forest: &mut B, baseline: &B, ops: &[Op], end: usize) -> usize {
+        let snapshot = end
+            .checked_sub(1)
+            .and_then(|last| self.cache.pop_till_snapshot_lte(&ops[last].id));
+        match snapshot {
+            Some((id, snapshot)) => {
+                *forest = snapshot.clone();
+                let id = *id;
+                ops.partition_point(|op| op.id <= id)
+            }
+            None => {
+                self.cache = Default::default();
+                *forest = baseline.clone();
+                0
+            }
+        }
+    }
+
+    fn state_at(&self, _forest: &B, baseline: &B, ops: &[Op], end: usize) -> B {
+        let (mut forest, start) = match end
+            .checked_sub(1)
+            .and_then(|last| self.cache.snapshot_lte(&ops[last].id))
+        {
+            Some((id, snapshot)) => (snapshot.clone(), ops.partition_point(|op| op.id <= *id)),
+            None => (baseline.clone(), 0),
+        };
+        for op in &ops[start..end] {
+            apply_op(&mut forest, op);
+        }
+        forest
+    }
+}
+
+/// Record the state of the target before each op, and undo the ops one by one.
+///
+/// It suits a mutable backend like [`crate::mut_tree::Forest`]. An undone `New` op leaves
+/// its node in the forest, so the states that are not on the way to apply the ops again
+/// are replayed from the baseline.
+///
+/// The records may start after the first applied op, e.g. in a replica restored from a
+/// snapshot. Rewinding further replays the ops from the baseline.
+#[derive(Debug, Clone, Default)]
+pub struct UndoLog {
+    /// The state of the target before each of the last applied ops
+    records: Vec<Option<NodeState>>,
+}
+
+impl UndoLog {
+    fn undo<B: TreeBackend>(forest: &mut B, op: &Op, record: Option<NodeState>) {
+        let Some(record) = record else {
+            return;
+        };
+        match op.content {
+            OpContent::New { .. } => {}
+            OpContent::Move { target, .. } => {
+                mov_with_position(forest, target, record.parent, record.position);
+            }
+            OpContent::Delete(target) | OpContent::Undelete(target) => {
+                forest.set_deleted(target, record.deleted);
+            }
+        }
+    }
+}
+
+impl<B: TreeBackend> Strategy<B> for UndoLog {
+    fn apply(&mut self, forest: &mut B, op: &Op) {
+        self.records.push(forest.node_state(op.target()));
+        apply_op(forest, op);
+    }
+
+    fn rewind(&mut self, forest: &mut B, baseline: &B, ops: &[Op], end: usize) -> usize {
+        let Some(start) = end.checked_sub(ops.len() - self.records.len()) else {
+            self.records.clear();
+            *forest = baseline.clone();
+            return 0;
+        };
+        for (op, record) in ops[end..].iter().zip(self.records.drain(start..)).rev() {
+            Self::undo(forest, op, record);
+        }
+        end
+    }
+
+    fn state_at(&self, _forest: &B, baseline: &B, ops: &[Op], end: usize) -> B {
+        let mut forest = baseline.clone();
+        for op in &ops[..end] {
+            apply_op(&mut forest, op);
+        }
+        forest
+    }
+
+    fn node_before(
+        &self,
+        forest: &B,
+        baseline: &B,
+        ops: &[Op],
+        i: usize,
+        node: ID,
+    ) -> Option<NodeState> {
+        let first = ops.len() - self.records.len();
+        if i >= first && ops[i].target() == node {
+            return self.records[i - first].clone();
+        }
+        self.state_at(forest, baseline, ops, i).node_state(node)
+    }
+}
@@ -0,0 +1,248 @@
+use std::mem::take;
+
+use super::{Counter, Crdt, EditError, Lamport, OpContent, Strategy, TreeBackend, ID};
+
+/// Undo and redo the edits of the local client of a [`Crdt`].
+///
+/// The local ops are grouped into steps by [`UndoManager::checkpoint`]. Undoing a step
+/// emits new ops that restore the state before each op of the step, so undoing is synced
+/// to other replicas like any other edit. The state to restore is looked up when undoing,
+/// at the position of the op among all the merged ops, so it stays correct after
+/// concurrent remote ops are merged.
+///
+/// The ops compacted by [`Crdt::compact`] are skipped, so they are never undone.
+/// It works with any backend and strategy of the CRDT.
+///
+/// # Example
+///
+/// ```
+/// use movable_tree::crdt_undo::{Crdt, UndoManager};
+/// let mut crdt = Crdt::new(1);
+/// let mut undo = UndoManager::new(&crdt);
+/// let a = crdt.new_node(None).unwrap();
+/// let b = crdt.new_node(None).unwrap();
+/// undo.checkpoint(&crdt);
+/// crdt.mov(b, Some(a)).unwrap();
+/// undo.undo(&mut crdt).unwrap();
+/// assert_eq!(crdt.forest().parent_of(b), None);
+/// undo.redo(&mut crdt).unwrap();
+/// assert_eq!(crdt.forest().parent_of(b), Some(a));
+/// ```
+#[derive(Debug, Clone)]
+pub struct UndoManager {
+    /// The local ops before this counter have been recorded
+    seen: Counter,
+    /// The local ops after the last checkpoint
+    current: Vec<ID>,
+    undo_stack: Vec<Vec<ID>>,
+    redo_stack: Vec<Vec<ID>>,
+}
+
+impl UndoManager {
+    /// Track the local ops of `crdt` from now on
+    pub fn new<B: TreeBackend, S: Strategy<B>>(crdt: &Crdt<B, S>) -> Self {
+        Self {
+            seen: crdt.log_len(crdt.client) as Counter,
+            current: Vec::new(),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+        }
+    }
+
+    /// Group the local ops since the last checkpoint into an undo step.
+    ///
+    /// New local ops clear the redo steps.
+    pub fn checkpoint<B: TreeBackend, S: Strategy<B>>(&mut self, crdt: &Crdt<B, S>) {
+        let len = crdt.log_len(crdt.client) as Counter;
+        if len > self.seen {
+            self.current
+                .extend(crdt.log_since(crdt.client, self.seen).iter().map(|x| x.id));
+            self.seen = len;
+            self.redo_stack.clear();
+        }
+        if !self.current.is_empty() {
+            self.undo_stack.push(take(&mut self.current));
+        }
+    }
+
+    /// Undo the last step. Return false if there is nothing to undo.
+    ///
+    /// Return Err if the replica can't create more ops, see [`EditError::LamportOverflow`].
+    /// Nothing is undone then, and the step is kept.
+    pub fn undo<B: TreeBackend, S: Strategy<B>>(
+        &mut self,
+        crdt: &mut Crdt<B, S>,
+    ) -> Result<bool, EditError> {
+        self.checkpoint(crdt);
+        let Some(step) = self.undo_stack.pop() else {
+            return Ok(false);
+        };
+        let inverse = match self.revert(crdt, &step) {
+            Ok(inverse) => inverse,
+            Err(err) => {
+                self.undo_stack.push(step);
+                return Err(err);
+            }
+        };
+        self.redo_stack.push(inverse);
+        Ok(true)
+    }
+
+    /// Redo the last undone step. Return false if there is nothing to redo.
+    ///
+    /// Return Err like [`UndoManager::undo`], and keep the step then.
+    pub fn redo<B: TreeBackend, S: Strategy<B>>(
+        &mut self,
+        crdt: &mut Crdt<B, S>,
+    ) -> Result<bool, EditError> {
+        self.checkpoint(crdt);
+        let Some(step) = self.redo_stack.pop() else {
+            return Ok(false);
+        };
+        let inverse = match self.revert(crdt, &step) {
+            Ok(inverse) => inverse,
+            Err(err) => {
+                self.redo_stack.push(step);
+                return Err(err);
+            }
+        };
+        self.undo_stack.push(inverse);
+        Ok(true)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty() || !self.current.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Emit the inverse ops of the step in reverse order, and return their ids.
+    ///
+    /// Emit nothing and return Err if there's no room for all of them.
+    fn revert<B: TreeBackend, S: Strategy<B>>(
+        &mut self,
+        crdt: &mut Crdt<B, S>,
+        step: &[ID],
+    ) -> Result<Vec<ID>, EditError> {
+        // the inverses only depend on the ops before the step, not on the new ops
+        let inverses: Vec<OpContent> = step
+            .iter()
+            .rev()
+            .filter_map(|id| crdt.inverse_of(*id))
+            .collect();
+        // the lamport of the last op must be less than the maximum
+        if crdt.next_lamport as u64 + inverses.len() as u64 > Lamport::MAX as u64 {
+            return Err(EditError::LamportOverflow);
+        }
+        let start = crdt.log_len(crdt.client) as Counter;
+        for content in inverses {
+            crdt.push_content(content)?;
+        }
+        self.seen = crdt.log_len(crdt.client) as Counter;
+        Ok(crdt
+            .log_since(crdt.client, start)
+            .iter()
+            .map(|x| x.id)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::UndoManager;
+    use crate::crdt_undo::{Crdt, EditError, Lamport, Op, OpContent, ID};
+
+    #[test]
+    fn undo_redo() {
+        let mut crdt = Crdt::new(1);
+        let mut undo = UndoManager::new(&crdt);
+        assert!(!undo.undo(&mut crdt).unwrap());
+        let a = crdt.new_node(None).unwrap();
+        let b = crdt.new_node(None).unwrap();
+        let c = crdt.new_node(Some(a)).unwrap();
+        undo.checkpoint(&crdt);
+        crdt.mov(b, Some(a)).unwrap();
+        crdt.delete(c).unwrap();
+        undo.checkpoint(&crdt);
+        crdt.mov_to(b, Some(a), 0).unwrap();
+        let latest = crdt.forest().clone();
+
+        // undo the steps one by one
+        assert!(undo.undo(&mut crdt).unwrap());
+        assert_eq!(crdt.children_ordered(Some(a)), vec![c, b]);
+        assert!(undo.undo(&mut crdt).unwrap());
+        assert_eq!(crdt.forest().parent_of(b), None);
+        assert!(!crdt.forest().is_deleted(c));
+        assert!(undo.undo(&mut crdt).unwrap());
+        assert!([a, b, c].iter().all(|x| crdt.forest().is_deleted(*x)));
+        assert!(!undo.undo(&mut crdt).unwrap());
+
+        while undo.redo(&mut crdt).unwrap() {}
+        assert_eq!(crdt.forest().len(), latest.len());
+        for (id, node) in latest.iter() {
+            let redone = crdt.forest().get(id).unwrap();
+            assert_eq!(redone.parent(), node.parent());
+            assert_eq!(redone.is_deleted(), node.is_deleted());
+        }
+        assert_eq!(crdt.children_ordered(Some(a)), vec![b, c]);
+
+        // a new edit clears the redo steps
+        undo.undo(&mut crdt).unwrap();
+        crdt.mov(a, None).unwrap();
+        assert!(!undo.redo(&mut crdt).unwrap());
+        assert!(undo.can_undo());
+    }
+
+    #[test]
+    fn undo_after_merge() {
+        let mut a = Crdt::new(2);
+        let mut b = Crdt::new(1);
+        let x = a.new_node(None).unwrap();
+        let y = a.new_node(None).unwrap();
+        let z = a.new_node(None).unwrap();
+        b.merge(&a).unwrap();
+
+        let mut undo = UndoManager::new(&a);
+        b.mov(z, Some(y)).unwrap();
+        a.mov(z, Some(x)).unwrap();
+        a.merge(&b).unwrap();
+        b.merge(&a).unwrap();
+        // b's move was applied before a's move, so undoing a's move restores it
+        assert_eq!(a.forest().parent_of(z), Some(x));
+        assert!(undo.undo(&mut a).unwrap());
+        assert_eq!(a.forest().parent_of(z), Some(y));
+
+        // the undo is synced like other ops
+        b.merge(&a).unwrap();
+        assert_eq!(a.forest(), b.forest());
+        assert!(undo.redo(&mut a).unwrap());
+        b.merge(&a).unwrap();
+        assert_eq!(b.forest().parent_of(z), Some(x));
+    }
+
+    #[test]
+    fn undo_overflow() {
+        let mut crdt = Crdt::new(1);
+        let a = crdt.new_node(None).unwrap();
+        let b = crdt.new_node(None).unwrap();
+        let mut undo = UndoManager::new(&crdt);
+        crdt.mov(b, Some(a)).unwrap();
+        crdt.delete(a).unwrap();
+        undo.checkpoint(&crdt);
+        // a remote op leaves room for one of the two inverse ops
+        let remote = ID::new(Lamport::MAX - 2, 3);
+        crdt.import_ops([Op::new(remote, 0, OpContent::Delete(b))])
+            .unwrap();
+        let forest = crdt.forest().clone();
+        let version = crdt.version();
+
+        assert_eq!(undo.undo(&mut crdt), Err(EditError::LamportOverflow));
+        // nothing is undone, and the step is kept
+        assert_eq!(crdt.forest(), &forest);
+        assert_eq!(crdt.version(), version);
+        assert_eq!(undo.undo_stack.len(), 1);
+        assert!(!undo.can_redo());
+    }
+}
@@ -0,0 +1,630 @@
+//! A compact binary format for batches of CRDT ops.
+//!
+//! The ops are stored column by column:
+//!
+//! ```log
+//! version: u8
+//! clients: the table of the clients referenced in the batch
+//! runs:    (client index, length) of each run of ops from the same client
+//! lamport: the delta of the lamport of each op from the previous op
+//! counter: the delta of the counter of each op from the previous op
+//! kind:    New / Move / Delete / Undelete of each op
+//! content: the ids and positions in the content of each op
+//! ```
+//!
+//! Ops exported from a replica are grouped by client, and the lamports and counters
+//! of a client grow by one in most cases, so the runs and deltas are short.
+//! All the integers are LEB128 varints, and the signed deltas are zigzag encoded.
+//! The ids in the content are `(client index, lamport)`.
+//!
+//! A snapshot of a replica stores its forests next to its ops, so it can be restored
+//! without applying the ops:
+//!
+//! ```log
+//! version:  u8
+//! client:   the client of the replica
+//! baseline: 0, or 1 followed by the last compacted id, the compacted version and the forest
+//! version:  (client, number of ops) of each client
+//! forest:   the live forest
+//! ops:      the op log as a batch, prefixed by its length
+//! pending:  the ops waiting for their dependencies as a batch, prefixed by its length
+//! ```
+//!
+//! A forest is its client table followed by the nodes in preorder. Each node is its id,
+//! position, deletion flag and number of children, and the children follow it in order.
+use std::fmt::Display;
+
+use crate::fractional_index::FractionalIndex;
+
+/// The version of the format written by the encoder
+pub const ENCODING_VERSION: u8 = 1;
+/// The version of the snapshot format
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+const KIND_NEW: u8 = 0;
+const KIND_MOVE: u8 = 1;
+const KIND_DELETE: u8 = 2;
+const KIND_UNDELETE: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The batch was written by an unknown version of the format
+    UnsupportedVersion(u8),
+    /// The input ended in the middle of a value
+    UnexpectedEnd,
+    /// A varint is longer than its type allows
+    InvalidVarint,
+    /// An op kind other than New, Move, Delete and Undelete
+    InvalidKind(u8),
+    /// A client index points outside of the client table
+    InvalidClient(u64),
+    /// A lamport or counter is out of the range of its type
+    Overflow,
+    /// A position is not a valid fractional index
+    InvalidPosition,
+    /// The input has more bytes after the batch
+    TrailingBytes,
+    /// A flag byte other than 0 and 1
+    InvalidFlag,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported encoding version {}", v),
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeError::InvalidVarint => write!(f, "invalid varint"),
+            DecodeError::InvalidKind(k) => write!(f, "invalid op kind {}", k),
+            DecodeError::InvalidClient(i) => write!(f, "client index {} is out of bound", i),
+            DecodeError::Overflow => write!(f, "lamport or counter overflows"),
+            DecodeError::InvalidPosition => write!(f, "invalid fractional index"),
+            DecodeError::TrailingBytes => write!(f, "unexpected bytes after the batch"),
+            DecodeError::InvalidFlag => write!(f, "invalid flag"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// The id of an op, `(lamport, client)`
+pub(crate) type RawId = (u32, u64);
+
+/// The common shape of the ops of both CRDTs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RawOp {
+    pub id: RawId,
+    pub counter: u32,
+    pub content: RawContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RawContent {
+    New {
+        parent: Option<RawId>,
+        position: FractionalIndex,
+    },
+    Move {
+        target: RawId,
+        parent: Option<RawId>,
+        position: FractionalIndex,
+    },
+    Delete(RawId),
+    Undelete(RawId),
+}
+
+/// A node of a forest in preorder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RawNode {
+    pub id: RawId,
+    pub position: FractionalIndex,
+    pub deleted: bool,
+    /// The number of children, which follow the node
+    pub children: usize,
+}
+
+/// The state of a replica
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RawSnapshot {
+    pub client: u64,
+    pub baseline: Option<RawBaseline>,
+    pub version: Vec<(u64, u32)>,
+    pub forest: Vec<RawNode>,
+    pub ops: Vec<RawOp>,
+    pub pending: Vec<RawOp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RawBaseline {
+    pub last: RawId,
+    pub version: Vec<(u64, u32)>,
+    pub forest: Vec<RawNode>,
+}
+
+pub(crate) fn encode_snapshot(snapshot: &RawSnapshot) -> Vec<u8> {
+    let mut ans = vec![SNAPSHOT_VERSION];
+    write_varint(&mut ans, snapshot.client);
+    match &snapshot.baseline {
+        None => ans.push(0),
+        Some(baseline) => {
+            ans.push(1);
+            write_varint(&mut ans, baseline.last.1);
+            write_varint(&mut ans, baseline.last.0 as u64);
+            write_version(&mut ans, &baseline.version);
+            write_forest(&mut ans, &baseline.forest);
+        }
+    }
+    write_version(&mut ans, &snapshot.version);
+    write_forest(&mut ans, &snapshot.forest);
+    write_bytes(&mut ans, &encode(&snapshot.ops));
+    write_bytes(&mut ans, &encode(&snapshot.pending));
+    ans
+}
+
+pub(crate) fn decode_snapshot(bytes: &[u8]) -> Result<RawSnapshot, DecodeError> {
+    let mut reader = Reader { bytes };
+    let version = reader.byte()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let client = reader.varint()?;
+    let baseline = match reader.byte()? {
+        0 => None,
+        1 => {
+            let client = reader.varint()?;
+            Some(RawBaseline {
+                last: (reader.lamport()?, client),
+                version: reader.version()?,
+                forest: reader.forest()?,
+            })
+        }
+        _ => return Err(DecodeError::InvalidFlag),
+    };
+    let version = reader.version()?;
+    let forest = reader.forest()?;
+    let len = reader.len()?;
+    let ops = decode(reader.take(len)?)?;
+    let len = reader.len()?;
+    let pending = decode(reader.take(len)?)?;
+    if !reader.bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+
+    Ok(RawSnapshot {
+        client,
+        baseline,
+        version,
+        forest,
+        ops,
+        pending,
+    })
+}
+
+fn write_version(buf: &mut Vec<u8>, version: &[(u64, u32)]) {
+    write_varint(buf, version.len() as u64);
+    for (client, len) in version {
+        write_varint(buf, *client);
+        write_varint(buf, *len as u64);
+    }
+}
+
+fn write_forest(buf: &mut Vec<u8>, nodes: &[RawNode]) {
+    let mut clients: Vec<u64> = Vec::new();
+    let mut client_index = fxhash::FxHashMap::default();
+    let mut body = Vec::new();
+    for node in nodes {
+        let index = *client_index.entry(node.id.1).or_insert_with(|| {
+            clients.push(node.id.1);
+            (clients.len() - 1) as u64
+        });
+        write_varint(&mut body, index);
+        write_varint(&mut body, node.id.0 as u64);
+        write_bytes(&mut body, node.position.as_bytes());
+        body.push(node.deleted as u8);
+        write_varint(&mut body, node.children as u64);
+    }
+
+    write_varint(buf, clients.len() as u64);
+    for client in clients {
+        write_varint(buf, client);
+    }
+    write_varint(buf, nodes.len() as u64);
+    buf.extend_from_slice(&body);
+}
+
+pub(crate) fn encode(ops: &[RawOp]) -> Vec<u8> {
+    let mut clients: Vec<u64> = Vec::new();
+    let mut client_index = fxhash::FxHashMap::default();
+    let mut index_of = |client: u64| -> u64 {
+        *client_index.entry(client).or_insert_with(|| {
+            clients.push(client);
+            (clients.len() - 1) as u64
+        })
+    };
+
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for op in ops {
+        let client = index_of(op.id.1);
+        match runs.last_mut() {
+            Some((c, len)) if *c == client => *len += 1,
+            _ => runs.push((client, 1)),
+        }
+    }
+
+    let mut lamports = Vec::new();
+    let mut counters = Vec::new();
+    let mut kinds = Vec::with_capacity(ops.len());
+    let mut contents = Vec::new();
+    let (mut last_lamport, mut last_counter) = (0, 0);
+    for op in ops {
+        write_varint(&mut lamports, zigzag(op.id.0 as i64 - last_lamport));
+        write_varint(&mut counters, zigzag(op.counter as i64 - last_counter));
+        last_lamport = op.id.0 as i64;
+        last_counter = op.counter as i64;
+        match &op.content {
+            RawContent::New { parent, position } => {
+                kinds.push(KIND_NEW);
+                write_parent(&mut contents, *parent, &mut index_of);
+                write_bytes(&mut contents, position.as_bytes());
+            }
+            RawContent::Move {
+                target,
+                parent,
+                position,
+            } => {
+                kinds.push(KIND_MOVE);
+                write_id(&mut contents, *target, &mut index_of);
+                write_parent(&mut contents, *parent, &mut index_of);
+                write_bytes(&mut contents, position.as_bytes());
+            }
+            RawContent::Delete(target) => {
+                kinds.push(KIND_DELETE);
+                write_id(&mut contents, *target, &mut index_of);
+            }
+            RawContent::Undelete(target) => {
+                kinds.push(KIND_UNDELETE);
+                write_id(&mut contents, *target, &mut index_of);
+            }
+        }
+    }
+
+    let mut ans = vec![ENCODING_VERSION];
+    write_varint(&mut ans, clients.len() as u64);
+    for client in clients {
+        write_varint(&mut ans, client);
+    }
+    write_varint(&mut ans, runs.len() as u64);
+    for (client, len) in runs {
+        write_varint(&mut ans, client);
+        write_varint(&mut ans, len);
+    }
+    ans.extend_from_slice(&lamports);
+    ans.extend_from_slice(&counters);
+    ans.extend_from_slice(&kinds);
+    ans.extend_from_slice(&contents);
+    ans
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<Vec<RawOp>, DecodeError> {
+    let mut reader = Reader { bytes };
+    let version = reader.byte()?;
+    if version != ENCODING_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    // every client takes at least one byte, so the lengths can't exceed the input
+    let clients_len = reader.len()?;
+    let mut clients = Vec::with_capacity(clients_len);
+    for _ in 0..clients_len {
+        clients.push(reader.varint()?);
+    }
+    let client_at = |index: u64| -> Result<u64, DecodeError> {
+        clients
+            .get(usize::try_from(index).map_err(|_| DecodeError::InvalidClient(index))?)
+            .copied()
+            .ok_or(DecodeError::InvalidClient(index))
+    };
+
+    let runs_len = reader.len()?;
+    let mut op_clients = Vec::new();
+    for _ in 0..runs_len {
+        let client = client_at(reader.varint()?)?;
+        let len = reader.len()?;
+        // every op takes at least one byte in each column
+        if op_clients.len() + len > reader.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        op_clients.resize(op_clients.len() + len, client);
+    }
+
+    let n = op_clients.len();
+    let mut lamports = Vec::with_capacity(n);
+    let mut last = 0i64;
+    for _ in 0..n {
+        last = last
+            .checked_add(unzigzag(reader.varint()?))
+            .ok_or(DecodeError::Overflow)?;
+        lamports.push(u32::try_from(last).map_err(|_| DecodeError::Overflow)?);
+    }
+    let mut counters = Vec::with_capacity(n);
+    let mut last = 0i64;
+    for _ in 0..n {
+        last = last
+            .checked_add(unzigzag(reader.varint()?))
+            .ok_or(DecodeError::Overflow)?;
+        counters.push(u32::try_from(last).map_err(|_| DecodeError::Overflow)?);
+    }
+    let kinds = reader.take(n)?;
+
+    let mut ans = Vec::with_capacity(n);
+    for i in 0..n {
+        let content = match kinds[i] {
+            KIND_NEW => RawContent::New {
+                parent: reader.parent(&client_at)?,
+                position: reader.position()?,
+            },
+            KIND_MOVE => RawContent::Move {
+                target: reader.id(&client_at)?,
+                parent: reader.parent(&client_at)?,
+                position: reader.position()?,
+            },
+            KIND_DELETE => RawContent::Delete(reader.id(&client_at)?),
+            KIND_UNDELETE => RawContent::Undelete(reader.id(&client_at)?),
+            kind => return Err(DecodeError::InvalidKind(kind)),
+        };
+        ans.push(RawOp {
+            id: (lamports[i], op_clients[i]),
+            counter: counters[i],
+            content,
+        });
+    }
+
+    if !reader.bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+
+    Ok(ans)
+}
+
+fn zigzag(x: i64) -> u64 {
+    ((x << 1) ^ (x >> 63)) as u64
+}
+
+fn unzigzag(x: u64) -> i64 {
+    ((x >> 1) as i64) ^ -((x & 1) as i64)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut x: u64) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_id(buf: &mut Vec<u8>, id: RawId, index_of: &mut impl FnMut(u64) -> u64) {
+    write_varint(buf, index_of(id.1));
+    write_varint(buf, id.0 as u64);
+}
+
+/// `None` is written as 0, and the client index of `Some` is shifted by one
+fn write_parent(buf: &mut Vec<u8>, parent: Option<RawId>, index_of: &mut impl FnMut(u64) -> u64) {
+    match parent {
+        None => write_varint(buf, 0),
+        Some(id) => {
+            write_varint(buf, index_of(id.1) + 1);
+            write_varint(buf, id.0 as u64);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let (&first, rest) = self.bytes.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+        self.bytes = rest;
+        Ok(first)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (ans, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(ans)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut ans: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u64;
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::InvalidVarint);
+            }
+            ans |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(ans);
+            }
+        }
+        Err(DecodeError::InvalidVarint)
+    }
+
+    /// A length of items that take at least one byte each
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        let len = self.varint()?;
+        if len > self.bytes.len() as u64 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        Ok(len as usize)
+    }
+
+    fn lamport(&mut self) -> Result<u32, DecodeError> {
+        u32::try_from(self.varint()?).map_err(|_| DecodeError::Overflow)
+    }
+
+    fn id(
+        &mut self,
+        client_at: &impl Fn(u64) -> Result<u64, DecodeError>,
+    ) -> Result<RawId, DecodeError> {
+        let client = client_at(self.varint()?)?;
+        Ok((self.lamport()?, client))
+    }
+
+    fn parent(
+        &mut self,
+        client_at: &impl Fn(u64) -> Result<u64, DecodeError>,
+    ) -> Result<Option<RawId>, DecodeError> {
+        match self.varint()? {
+            0 => Ok(None),
+            index => {
+                let client = client_at(index - 1)?;
+                Ok(Some((self.lamport()?, client)))
+            }
+        }
+    }
+
+    fn position(&mut self) -> Result<FractionalIndex, DecodeError> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        FractionalIndex::from_bytes(bytes.to_vec()).ok_or(DecodeError::InvalidPosition)
+    }
+
+    fn version(&mut self) -> Result<Vec<(u64, u32)>, DecodeError> {
+        let len = self.len()?;
+        let mut ans = Vec::with_capacity(len);
+        for _ in 0..len {
+            let client = self.varint()?;
+            ans.push((client, self.lamport()?));
+        }
+        Ok(ans)
+    }
+
+    fn forest(&mut self) -> Result<Vec<RawNode>, DecodeError> {
+        let clients_len = self.len()?;
+        let mut clients = Vec::with_capacity(clients_len);
+        for _ in 0..clients_len {
+            clients.push(self.varint()?);
+        }
+
+        let len = self.len()?;
+        let mut ans = Vec::with_capacity(len);
+        for _ in 0..len {
+            let index = self.varint()?;
+            let client = usize::try_from(index)
+                .ok()
+                .and_then(|i| clients.get(i))
+                .copied()
+                .ok_or(DecodeError::InvalidClient(index))?;
+            let lamport = self.lamport()?;
+            let position = self.position()?;
+            let deleted = match self.byte()? {
+                0 => false,
+                1 => true,
+                _ => return Err(DecodeError::InvalidFlag),
+            };
+            // every child takes at least one byte
+            let children = self.len()?;
+            ans.push(RawNode {
+                id: (lamport, client),
+                position,
+                deleted,
+                children,
+            });
+        }
+        Ok(ans)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn ops() -> Vec<RawOp> {
+        let mut ans = Vec::new();
+        let mut position = FractionalIndex::default();
+        for client in [3, u64::MAX, 3] {
+            for i in 0..50u32 {
+                position = FractionalIndex::between(Some(&position), None).with_suffix(client);
+                let content = match i % 4 {
+                    0 => RawContent::New {
+                        parent: (i > 0).then(|| (i - 1, client)),
+                        position: position.clone(),
+                    },
+                    1 => RawContent::Move {
+                        target: (i / 2, 7),
+                        parent: None,
+                        position: position.clone(),
+                    },
+                    2 => RawContent::Delete((u32::MAX, client)),
+                    _ => RawContent::Undelete((i, client)),
+                };
+                ans.push(RawOp {
+                    id: (i * 2, client),
+                    counter: i,
+                    content,
+                });
+            }
+        }
+        ans
+    }
+
+    #[test]
+    fn round_trip() {
+        let ops = ops();
+        let bytes = encode(&ops);
+        assert_eq!(decode(&bytes).unwrap(), ops);
+        assert_eq!(decode(&encode(&[])).unwrap(), vec![]);
+        // the lamports and counters take one byte each
+        let positions: usize = ops
+            .iter()
+            .map(|op| match &op.content {
+                RawContent::New { position, .. } | RawContent::Move { position, .. } => {
+                    position.as_bytes().len()
+                }
+                RawContent::Delete(_) | RawContent::Undelete(_) => 0,
+            })
+            .sum();
+        assert!(bytes.len() < positions + ops.len() * 9);
+    }
+
+    #[test]
+    fn invalid_input() {
+        let bytes = encode(&ops());
+        assert_eq!(decode(&[]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(decode(&[2]), Err(DecodeError::UnsupportedVersion(2)));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(decode(&trailing), Err(DecodeError::TrailingBytes));
+        for i in 0..bytes.len() {
+            assert!(decode(&bytes[..i]).is_err());
+        }
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..10_000 {
+            let mut corrupted = bytes.clone();
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..corrupted.len());
+                corrupted[i] = rng.gen();
+            }
+            let _ = decode(&corrupted);
+            let random: Vec<u8> = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect();
+            let _ = decode(&random);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+/// A position key that can always generate a new key between any two keys.
+///
+/// The key is a fraction in base 256, `0.b0 b1 b2 ...`. Keys never end with a zero byte,
+/// so comparing the bytes lexicographically is the same as comparing the fractions.
+///
+/// Two replicas generating a key at the same spot concurrently would get the same key,
+/// so [`FractionalIndex::with_suffix`] should be used to make the keys of different replicas differ.
+///
+/// The bytes are shared by the clones, so copying a forest node that carries a key is cheap.
+///
+/// # Example
+///
+/// ```
+/// use movable_tree::fractional_index::FractionalIndex;
+/// let a = FractionalIndex::between(None, None);
+/// let b = FractionalIndex::between(Some(&a), None);
+/// let c = FractionalIndex::between(Some(&a), Some(&b));
+/// assert!(a < c && c < b);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(try_from = "Vec<u8>")
+)]
+pub struct FractionalIndex(Arc<[u8]>);
+
+const MIDDLE: u8 = 128;
+
+impl FractionalIndex {
+    /// Generate a key between `lower` and `upper`. `None` means unbounded.
+    ///
+    /// If `upper` is not greater than `lower`, it's ignored and the key is only greater than `lower`.
+    pub fn between(lower: Option<&FractionalIndex>, upper: Option<&FractionalIndex>) -> Self {
+        let lower: &[u8] = lower.map(|x| x.as_bytes()).unwrap_or(&[]);
+        let mut upper: Option<&[u8]> = upper.map(|x| x.as_bytes());
+        if let Some(u) = upper {
+            if u <= lower {
+                upper = None;
+            }
+        }
+
+        let mut ans = Vec::new();
+        let mut i = 0;
+        loop {
+            let lower_ended = i >= lower.len();
+            let lo = lower.get(i).copied().unwrap_or(0);
+            match upper {
+                None => {
+                    if lower_ended {
+                        ans.push(MIDDLE);
+                        break;
+                    } else if lo < u8::MAX {
+                        // a small step, so appending many keys grows the length slowly
+                        ans.push(lo + 1);
+                        break;
+                    } else {
+                        ans.push(lo);
+                    }
+                }
+                Some(u) => {
+                    let hi = u.get(i).copied().unwrap_or(0);
+                    if lo == hi {
+                        ans.push(lo);
+                    } else if lower_ended && hi >= 2 {
+                        // a small step, so prepending many keys grows the length slowly
+                        ans.push(hi - 1);
+                        break;
+                    } else if hi - lo > 1 {
+                        ans.push(lo + (hi - lo) / 2);
+                        break;
+                    } else {
+                        // any key greater than the rest of lower works
+                        ans.push(lo);
+                        upper = None;
+                    }
+                }
+            }
+
+            i += 1;
+        }
+
+        FractionalIndex(ans.into())
+    }
+
+    /// Append a suffix unique to the replica.
+    ///
+    /// It keeps the key between the bounds it was generated with, and makes
+    /// the keys generated by different replicas at the same spot distinct.
+    pub fn with_suffix(self, replica: u64) -> Self {
+        let mut bytes = self.0.to_vec();
+        let mut n = replica;
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                bytes.push(byte);
+                break;
+            }
+            bytes.push(byte | 0x80);
+        }
+        // the terminator keeps the key from ending with zero
+        bytes.push(1);
+        FractionalIndex(bytes.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Return `None` if the bytes are not a valid key, i.e. end with zero.
+    pub fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        if bytes.last() == Some(&0) {
+            return None;
+        }
+
+        Some(FractionalIndex(bytes.into()))
+    }
+}
+
+/// Serialized like a `Vec<u8>`
+#[cfg(feature = "serde")]
+impl serde::Serialize for FractionalIndex {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.as_bytes().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<Vec<u8>> for FractionalIndex {
+    type Error = &'static str;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::from_bytes(bytes).ok_or("a fractional index can't end with zero")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generate() {
+        let mut keys = vec![FractionalIndex::between(None, None)];
+        for _ in 0..1000 {
+            let key = FractionalIndex::between(keys.last(), None);
+            assert!(&key > keys.last().unwrap());
+            keys.push(key);
+        }
+        for _ in 0..1000 {
+            let key = FractionalIndex::between(None, keys.first());
+            assert!(&key < keys.first().unwrap());
+            keys.insert(0, key);
+        }
+        assert!(keys.iter().all(|x| x.as_bytes().len() <= 10));
+
+        // keep inserting at the same spot
+        let mut lower = keys[0].clone();
+        let upper = keys[1].clone();
+        for i in 0..1000 {
+            let key = FractionalIndex::between(Some(&lower), Some(&upper)).with_suffix(i);
+            assert!(
+                lower < key && key < upper,
+                "{:?} {:?} {:?}",
+                lower,
+                key,
+                upper
+            );
+            assert!(FractionalIndex::from_bytes(key.as_bytes().to_vec()).is_some());
+            lower = key;
+        }
+
+        let a = FractionalIndex::between(Some(&keys[5]), Some(&keys[6]));
+        assert_ne!(a.clone().with_suffix(1), a.clone().with_suffix(2));
+        assert!(FractionalIndex::between(Some(&keys[6]), Some(&keys[5])) > keys[6]);
+    }
+}
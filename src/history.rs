@@ -0,0 +1,227 @@
+use std::{collections::VecDeque, fmt::Debug, ops::RangeBounds};
+
+use fxhash::FxHashMap;
+
+use crate::{Error, Forest, IdTrait};
+
+/// The index of a version in [`History`]. The initial empty forest is version 0.
+pub type Version = usize;
+
+/// All the versions of a [`Forest`].
+///
+/// Every successful edit records a new version. Because cloning a forest is O(1)
+/// and the versions share their structure, recording a version is cheap.
+///
+/// # Example
+///
+/// ```
+/// use movable_tree::History;
+/// let mut history: History<usize> = History::new();
+/// history.mov(1, None).unwrap();
+/// history.tag("one root");
+/// let v = history.mov(2, Some(1)).unwrap();
+/// assert_eq!(v, 2);
+/// assert!(!history.at(1).unwrap().contains(2));
+/// assert!(history.at_tag("one root").unwrap().contains(1));
+/// assert!(history.latest().contains(2));
+/// ```
+#[derive(Clone)]
+pub struct History<ID, V = ()> {
+    /// `versions[i]` is the forest of version `start + i`
+    versions: VecDeque<Forest<ID, V>>,
+    start: Version,
+    tags: FxHashMap<String, Version>,
+}
+
+impl<ID: IdTrait, V: Debug> Debug for History<ID, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("History")
+            .field("start", &self.start)
+            .field("versions", &self.versions)
+            .field("tags", &self.tags)
+            .finish()
+    }
+}
+
+impl<ID: IdTrait, V: Clone> History<ID, V> {
+    pub fn new() -> Self {
+        Self::from_forest(Forest::new())
+    }
+
+    /// Create a history whose version 0 is `forest`
+    pub fn from_forest(forest: Forest<ID, V>) -> Self {
+        Self {
+            versions: VecDeque::from([forest]),
+            start: 0,
+            tags: Default::default(),
+        }
+    }
+
+    /// Apply `f` to a clone of the latest forest and record the result as a new version.
+    ///
+    /// Nothing is recorded if `f` returns Err.
+    pub fn apply(
+        &mut self,
+        f: impl FnOnce(&mut Forest<ID, V>) -> Result<(), Error<ID>>,
+    ) -> Result<Version, Error<ID>> {
+        let mut forest = self.latest().clone();
+        f(&mut forest)?;
+        self.versions.push_back(forest);
+        Ok(self.latest_version())
+    }
+
+    /// See [`Forest::mov`]. Return the new version.
+    pub fn mov(&mut self, node_id: ID, parent_id: Option<ID>) -> Result<Version, Error<ID>>
+    where
+        V: Default,
+    {
+        self.apply(|forest| forest.mov(node_id, parent_id))
+    }
+
+    /// See [`Forest::mov_to`]. Return the new version.
+    pub fn mov_to(
+        &mut self,
+        node_id: ID,
+        parent_id: Option<ID>,
+        index: usize,
+    ) -> Result<Version, Error<ID>>
+    where
+        V: Default,
+    {
+        self.apply(|forest| forest.mov_to(node_id, parent_id, index))
+    }
+
+    /// See [`Forest::delete`]. Return the new version.
+    pub fn delete(&mut self, node_id: ID) -> Result<Version, Error<ID>> {
+        self.apply(|forest| forest.delete(node_id))
+    }
+
+    /// See [`Forest::undo_delete`]. Return the new version.
+    pub fn undo_delete(&mut self, node_id: ID) -> Result<Version, Error<ID>> {
+        self.apply(|forest| forest.undo_delete(node_id))
+    }
+
+    pub fn latest(&self) -> &Forest<ID, V> {
+        self.versions.back().unwrap()
+    }
+
+    pub fn latest_version(&self) -> Version {
+        self.start + self.versions.len() - 1
+    }
+
+    /// The oldest version that has not been pruned
+    pub fn first_version(&self) -> Version {
+        self.start
+    }
+
+    /// Get the forest of the version. Return `None` if it's pruned or doesn't exist yet.
+    pub fn at(&self, version: Version) -> Option<&Forest<ID, V>> {
+        self.versions.get(version.checked_sub(self.start)?)
+    }
+
+    /// Iterate over the versions in the range that have not been pruned
+    pub fn range(
+        &self,
+        range: impl RangeBounds<Version>,
+    ) -> impl Iterator<Item = (Version, &Forest<ID, V>)> + '_ {
+        let first = self.first_version();
+        let last = self.latest_version() + 1;
+        let start = match range.start_bound() {
+            std::ops::Bound::Included(&x) => x,
+            std::ops::Bound::Excluded(&x) => x.saturating_add(1),
+            std::ops::Bound::Unbounded => first,
+        }
+        .clamp(first, last);
+        let end = match range.end_bound() {
+            std::ops::Bound::Included(&x) => x.saturating_add(1),
+            std::ops::Bound::Excluded(&x) => x,
+            std::ops::Bound::Unbounded => usize::MAX,
+        }
+        .clamp(start, last);
+        self.versions
+            .range(start - first..end - first)
+            .enumerate()
+            .map(move |(i, forest)| (start + i, forest))
+    }
+
+    /// Label the latest version. An existing tag with the same name is overwritten.
+    pub fn tag(&mut self, name: impl Into<String>) {
+        self.tags.insert(name.into(), self.latest_version());
+    }
+
+    /// The version labelled by the tag
+    pub fn version_of_tag(&self, name: &str) -> Option<Version> {
+        self.tags.get(name).copied()
+    }
+
+    pub fn at_tag(&self, name: &str) -> Option<&Forest<ID, V>> {
+        self.at(self.version_of_tag(name)?)
+    }
+
+    /// Drop the versions older than `version`. The latest version is always kept.
+    ///
+    /// The tags of the dropped versions are removed.
+    pub fn prune_before(&mut self, version: Version) {
+        let version = version.min(self.latest_version());
+        while self.start < version {
+            self.versions.pop_front();
+            self.start += 1;
+        }
+        self.tags.retain(|_, v| *v >= version);
+    }
+
+    /// The number of versions kept
+    pub fn len(&self) -> usize {
+        self.versions.len()
+    }
+
+    /// Always false, because the latest version is always kept
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+}
+
+impl<ID: IdTrait, V: Clone> Default for History<ID, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn history() {
+        let mut history: History<usize> = History::new();
+        for i in 0..10 {
+            assert_eq!(history.mov(i, None).unwrap(), i + 1);
+        }
+        assert!(history.mov(20, Some(30)).is_err());
+        assert_eq!(history.latest_version(), 10);
+        history.tag("ten");
+        history.delete(0).unwrap();
+        assert!(history.latest().is_deleted(0));
+        assert!(!history.at_tag("ten").unwrap().is_deleted(0));
+        assert_eq!(history.at(3).unwrap().len(), 3);
+        assert!(history.at(12).is_none());
+
+        let versions: Vec<Version> = history.range(2..5).map(|(v, _)| v).collect();
+        assert_eq!(versions, vec![2, 3, 4]);
+        assert_eq!(history.range(..).count(), 12);
+        assert_eq!(history.range(12..).count(), 0);
+        assert_eq!(history.range(20..30).count(), 0);
+        assert_eq!(History::<usize>::new().range(5..).count(), 0);
+
+        history.prune_before(5);
+        assert_eq!(history.first_version(), 5);
+        assert!(history.at(4).is_none());
+        assert_eq!(history.at(5).unwrap().len(), 5);
+        assert_eq!(history.range(..=6).count(), 2);
+        assert_eq!(history.version_of_tag("ten"), Some(10));
+        history.prune_before(100);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.first_version(), 11);
+        assert!(history.at_tag("ten").is_none());
+    }
+}
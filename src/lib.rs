@@ -1,8 +1,19 @@
 #![doc = include_str!("../README.md")]
 
+pub mod crdt;
 pub mod crdt_snapshot;
 pub mod crdt_undo;
+pub mod encoding;
+pub mod fractional_index;
+pub mod history;
+mod link_cut;
 pub mod log_spaced_snapshots;
-mod mut_tree;
+pub mod mut_tree;
+mod order_tree;
+pub mod reclaim;
+pub mod storage;
+pub mod sync;
 mod tree;
+pub mod version_vector;
+pub use history::History;
 pub use tree::*;
@@ -0,0 +1,261 @@
+//! A link-cut tree used to answer ancestor queries in amortized O(log n).
+//!
+//! Only the hierarchy is stored. The nodes live in an arena and refer to each other by index.
+use std::hash::Hash;
+
+use fxhash::FxHashMap;
+
+const NIL: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    left: u32,
+    right: u32,
+    /// The parent in the splay tree, or the path-parent if the node is the root of its splay tree
+    parent: u32,
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            left: NIL,
+            right: NIL,
+            parent: NIL,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LinkCut<ID> {
+    index: FxHashMap<ID, u32>,
+    nodes: Vec<Node>,
+}
+
+impl<ID: Hash + Eq + Copy> LinkCut<ID> {
+    /// Build from the parent of each node. The parents must not form a cycle.
+    pub fn from_parents(parents: impl Iterator<Item = (ID, Option<ID>)>) -> Self {
+        let mut this = Self {
+            index: Default::default(),
+            nodes: Vec::new(),
+        };
+        for (id, parent) in parents {
+            let x = this.node(id);
+            if let Some(parent) = parent {
+                // every node is the only node on its preferred path,
+                // so the parent is its path-parent
+                this.nodes[x as usize].parent = this.node(parent);
+            }
+        }
+        this
+    }
+
+    fn node(&mut self, id: ID) -> u32 {
+        let nodes = &mut self.nodes;
+        *self.index.entry(id).or_insert_with(|| {
+            nodes.push(Node::default());
+            (nodes.len() - 1) as u32
+        })
+    }
+
+    /// Whether `ancestor` is an ancestor of `node`, or the same node
+    pub fn is_ancestor(&mut self, ancestor: ID, node: ID) -> bool {
+        if ancestor == node {
+            return true;
+        }
+        let (Some(&a), Some(&b)) = (self.index.get(&ancestor), self.index.get(&node)) else {
+            return false;
+        };
+        self.access(a);
+        // the last path joined by accessing b is the lowest common ancestor,
+        // or a node in another tree if they are not connected
+        self.access(b) == a
+    }
+
+    /// Same as [`LinkCut::is_ancestor`], but without splaying, so it can read a shared tree.
+    ///
+    /// It walks up the splay trees from `node` as they are, so it takes O(depth) in the
+    /// worst case rather than amortized O(log n).
+    pub fn is_ancestor_without_splay(&self, ancestor: ID, node: ID) -> bool {
+        if ancestor == node {
+            return true;
+        }
+        let (Some(&a), Some(&b)) = (self.index.get(&ancestor), self.index.get(&node)) else {
+            return false;
+        };
+        let a_path = self.splay_path(a);
+        let mut y = b;
+        loop {
+            // the ancestors of b on this preferred path are y and the nodes before it
+            let y_path = self.splay_path(y);
+            if a_path[0] == y_path[0] {
+                return self.precedes(&a_path, &y_path);
+            }
+            y = self.nodes[y_path[0] as usize].parent;
+            if y == NIL {
+                return false;
+            }
+        }
+    }
+
+    /// The nodes from the root of the splay tree of `x` down to `x`
+    fn splay_path(&self, mut x: u32) -> Vec<u32> {
+        let mut path = vec![x];
+        while !self.is_splay_root(x) {
+            x = self.nodes[x as usize].parent;
+            path.push(x);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Whether the last node of `a` comes before the last node of `b`, or is the same node,
+    /// in the order of their splay tree. Both paths must start at the same root.
+    fn precedes(&self, a: &[u32], b: &[u32]) -> bool {
+        let common = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+        match (a.get(common), b.get(common)) {
+            (None, None) => true,
+            // b is below a, after it if it's in the right subtree
+            (None, Some(&y)) => self.nodes[a[common - 1] as usize].right == y,
+            // a is below b, before it if it's in the left subtree
+            (Some(&x), None) => self.nodes[b[common - 1] as usize].left == x,
+            (Some(&x), Some(_)) => self.nodes[a[common - 1] as usize].left == x,
+        }
+    }
+
+    /// Make `parent` the parent of `child`. `child` must be a root.
+    pub fn link(&mut self, child: ID, parent: ID) {
+        let c = self.node(child);
+        let p = self.node(parent);
+        self.access(c);
+        debug_assert_eq!(self.nodes[c as usize].left, NIL);
+        self.nodes[c as usize].parent = p;
+    }
+
+    /// Detach `child` from its parent, if it has one. A new node is added as a root.
+    pub fn cut(&mut self, child: ID) {
+        let c = self.node(child);
+        self.access(c);
+        let left = self.nodes[c as usize].left;
+        if left != NIL {
+            self.nodes[left as usize].parent = NIL;
+            self.nodes[c as usize].left = NIL;
+        }
+    }
+
+    fn is_splay_root(&self, x: u32) -> bool {
+        let p = self.nodes[x as usize].parent;
+        p == NIL || (self.nodes[p as usize].left != x && self.nodes[p as usize].right != x)
+    }
+
+    fn rotate(&mut self, x: u32) {
+        let p = self.nodes[x as usize].parent;
+        let g = self.nodes[p as usize].parent;
+        let p_is_root = self.is_splay_root(p);
+        if self.nodes[p as usize].left == x {
+            let b = self.nodes[x as usize].right;
+            self.nodes[p as usize].left = b;
+            if b != NIL {
+                self.nodes[b as usize].parent = p;
+            }
+            self.nodes[x as usize].right = p;
+        } else {
+            let b = self.nodes[x as usize].left;
+            self.nodes[p as usize].right = b;
+            if b != NIL {
+                self.nodes[b as usize].parent = p;
+            }
+            self.nodes[x as usize].left = p;
+        }
+        self.nodes[p as usize].parent = x;
+        self.nodes[x as usize].parent = g;
+        if !p_is_root {
+            if self.nodes[g as usize].left == p {
+                self.nodes[g as usize].left = x;
+            } else {
+                self.nodes[g as usize].right = x;
+            }
+        }
+    }
+
+    fn splay(&mut self, x: u32) {
+        while !self.is_splay_root(x) {
+            let p = self.nodes[x as usize].parent;
+            if !self.is_splay_root(p) {
+                let g = self.nodes[p as usize].parent;
+                let zig_zig =
+                    (self.nodes[g as usize].left == p) == (self.nodes[p as usize].left == x);
+                if zig_zig {
+                    self.rotate(p);
+                } else {
+                    self.rotate(x);
+                }
+            }
+            self.rotate(x);
+        }
+    }
+
+    /// Make the path from the root to `x` preferred, and `x` the root of its splay tree.
+    ///
+    /// Return the last node where the path was joined.
+    fn access(&mut self, x: u32) -> u32 {
+        let mut last = NIL;
+        let mut y = x;
+        while y != NIL {
+            self.splay(y);
+            self.nodes[y as usize].right = last;
+            last = y;
+            y = self.nodes[y as usize].parent;
+        }
+        self.splay(x);
+        last
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn naive_is_ancestor(parents: &[Option<usize>], a: usize, mut b: usize) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match parents[b] {
+                Some(p) => b = p,
+                None => return false,
+            }
+        }
+    }
+
+    #[test]
+    fn random_moves() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let n = 50;
+        let mut parents: Vec<Option<usize>> = (0..n)
+            .map(|i| (i > 0 && rng.gen_bool(0.8)).then(|| rng.gen_range(0..i)))
+            .collect();
+        let mut tree = LinkCut::from_parents(parents.iter().copied().enumerate());
+        for _ in 0..5000 {
+            let a = rng.gen_range(0..n);
+            let b = rng.gen_range(0..n);
+            let c = rng.gen_range(0..n);
+            let d = rng.gen_range(0..n);
+            assert_eq!(
+                tree.is_ancestor_without_splay(c, d),
+                naive_is_ancestor(&parents, c, d)
+            );
+            let is_ancestor = tree.is_ancestor(a, b);
+            assert_eq!(is_ancestor, naive_is_ancestor(&parents, a, b));
+            if !is_ancestor {
+                tree.cut(a);
+                tree.link(a, b);
+                parents[a] = Some(b);
+            } else if rng.gen_bool(0.1) {
+                tree.cut(b);
+                parents[b] = None;
+            }
+        }
+    }
+}
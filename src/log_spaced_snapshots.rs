@@ -93,6 +93,18 @@ impl<K: Ord, T> LogSpacedSnapshots<K, T> {
             .map(|k| (k, self.cache.last_key_value().unwrap().1))
     }
 
+    /// Get the latest snapshot whose version <= k without removing anything
+    pub fn snapshot_lte(&self, k: &K) -> Option<(&K, &T)> {
+        let end = match self.keys.binary_search(k) {
+            Ok(n) => n + 1,
+            Err(n) => n,
+        };
+        self.cache
+            .range(..end)
+            .next_back()
+            .map(|(i, snapshot)| (&self.keys[*i], snapshot))
+    }
+
     pub fn cache_size(&self) -> usize {
         self.cache.len()
     }
@@ -144,15 +156,19 @@ mod test {
         for i in 0..10000 {
             cache.push(i, i);
         }
+        assert_eq!(cache.snapshot_lte(&6000), Some((&5119, &5119)));
+        assert_eq!(cache.snapshot_lte(&10000), Some((&9999, &9999)));
         let (v, s) = cache.pop_till_snapshot_lte(&9999).unwrap();
         assert_eq!(*v, 9999);
         assert_eq!(*s, 9999);
         let (v, s) = cache.pop_till_snapshot_lte(&9998).unwrap();
         assert_eq!(*v, 9998);
         assert_eq!(*s, 9998);
+        assert_eq!(cache.snapshot_lte(&6000), Some((&5119, &5119)));
         let (v, s) = cache.pop_till_snapshot_lte(&6000).unwrap();
         assert_eq!(*v, 5119);
         assert_eq!(*s, 5119);
+        assert!(cache.snapshot_lte(&2000).is_none());
         assert!(cache.pop_till_snapshot_lte(&2000).is_none());
     }
 }
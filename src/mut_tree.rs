@@ -1,138 +1,430 @@
-use std::{fmt::Debug, hash::Hash};
+use std::{
+    fmt::{Debug, Display},
+    hash::Hash,
+};
 
 use fxhash::FxHashMap;
 
+use crate::{
+    link_cut::LinkCut,
+    order_tree::{Key, OrderTree},
+};
+
+#[cfg(feature = "serde")]
+mod serde_impl;
+
 pub trait IdTrait: Hash + Eq + Clone + Copy + Debug {}
 impl<T: Hash + Eq + Clone + Copy + Debug> IdTrait for T {}
 
+/// A mutable forest. Each node carries a value of type `V`.
+///
+/// It keeps the ordered children of each node, like [`crate::Forest`].
 #[derive(Clone)]
-pub struct Forest<ID> {
-    map: FxHashMap<ID, TreeNode<ID>>,
+pub struct Forest<ID, V = ()> {
+    map: FxHashMap<ID, TreeNode<ID, V>>,
+    /// Ordered children of each node. Root nodes are stored under `None`.
+    /// Each node stores its key in the siblings, so it can be found in O(log n).
+    children: FxHashMap<Option<ID>, OrderTree<ID>>,
+    /// Optional index for the cycle check. See [`Forest::enable_ancestor_index`].
+    ancestor_index: Option<LinkCut<ID>>,
 }
 
-impl<ID: Hash + PartialEq + Eq> PartialEq for Forest<ID> {
+impl<ID: Hash + PartialEq + Eq, V: PartialEq> PartialEq for Forest<ID, V> {
     fn eq(&self, other: &Self) -> bool {
-        self.map == other.map
+        self.map == other.map && self.children == other.children
     }
 }
 
-impl<ID: Hash + PartialEq + Eq + Debug> Debug for Forest<ID> {
+impl<ID: Hash + PartialEq + Eq + Debug, V: Debug> Debug for Forest<ID, V> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Forest").field("map", &self.map).finish()
     }
 }
 
-impl<ID: Hash + PartialEq + Eq> Eq for Forest<ID> {}
+impl<ID: Hash + PartialEq + Eq, V: Eq> Eq for Forest<ID, V> {}
 
-/// Immutable tree node.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) struct TreeNode<ID> {
+/// Mutable tree node.
+#[derive(Debug, Clone, Copy)]
+pub struct TreeNode<ID, V = ()> {
     pub(crate) parent: Option<ID>,
     pub(crate) deleted: bool,
+    pub(crate) value: V,
+    /// The key of the node in the children of its parent
+    pub(crate) order: Key,
+}
+
+/// The keys are ignored, because the same order of siblings can be given by different keys
+impl<ID: PartialEq, V: PartialEq> PartialEq for TreeNode<ID, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.parent == other.parent && self.deleted == other.deleted && self.value == other.value
+    }
 }
 
-#[derive(Debug, Clone)]
-pub enum Error {
+impl<ID: Eq, V: Eq> Eq for TreeNode<ID, V> {}
+
+impl<ID: Copy, V> TreeNode<ID, V> {
+    /// The parent of the node. `None` if it's a root.
+    #[inline(always)]
+    pub fn parent(&self) -> Option<ID> {
+        self.parent
+    }
+
+    #[inline(always)]
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    #[inline(always)]
+    pub fn value(&self) -> &V {
+        &self.value
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<ID> {
+    /// The move would make a node its own ancestor
     CyclicMoveErr,
+    /// The new parent is not contained in the forest
+    ParentNotFound(ID),
+    /// The target node is not contained in the forest
+    NodeNotFound(ID),
+    /// The index is greater than the number of the new siblings
+    IndexOutOfBound { index: usize, len: usize },
+    /// The parent pointers form a loop or point to a missing node.
+    /// It can only happen if the forest was built from corrupted data.
+    CorruptedHierarchy,
+}
+
+impl<ID: Debug> Display for Error<ID> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::CyclicMoveErr => write!(f, "the move would cause a cycle in the tree"),
+            Error::ParentNotFound(id) => write!(f, "parent id {:?} does not exist", id),
+            Error::NodeNotFound(id) => write!(f, "node id {:?} does not exist", id),
+            Error::IndexOutOfBound { index, len } => {
+                write!(f, "index {} is out of bound, the len is {}", index, len)
+            }
+            Error::CorruptedHierarchy => write!(f, "the hierarchy of the forest is corrupted"),
+        }
+    }
 }
 
-impl<ID: IdTrait> Forest<ID> {
+impl<ID: Debug> std::error::Error for Error<ID> {}
+
+impl<ID: IdTrait, V> Forest<ID, V> {
     #[inline(always)]
     pub fn new() -> Self {
         Self {
             map: Default::default(),
+            children: Default::default(),
+            ancestor_index: None,
         }
     }
 
+    /// Maintain a link-cut tree, so the cycle check of each move takes amortized
+    /// O(log n) instead of O(depth). It's useful for deep trees.
+    ///
+    /// Return Err if the hierarchy is corrupted.
+    pub fn enable_ancestor_index(&mut self) -> Result<(), Error<ID>> {
+        // every node should be reachable from the roots, through the children of its parent
+        let consistent = self.children.iter().all(|(parent, children)| {
+            children
+                .iter()
+                .all(|x| self.map.get(x).map(|x| x.parent) == Some(*parent))
+        });
+        if !consistent {
+            return Err(Error::CorruptedHierarchy);
+        }
+
+        let mut stack: Vec<ID> = self.roots().collect();
+        let mut count = 0;
+        while let Some(id) = stack.pop() {
+            count += 1;
+            if count > self.map.len() {
+                break;
+            }
+            stack.extend(self.children(id));
+        }
+        if count != self.map.len() {
+            return Err(Error::CorruptedHierarchy);
+        }
+
+        self.ancestor_index = Some(LinkCut::from_parents(
+            self.map.iter().map(|(id, node)| (*id, node.parent)),
+        ));
+        Ok(())
+    }
+
+    /// Drop the index built by [`Forest::enable_ancestor_index`]
+    pub fn disable_ancestor_index(&mut self) {
+        self.ancestor_index = None;
+    }
+
     /// Move node into new_parent.
-    /// It will **create a new node** if node is not contained in the current map
+    /// It will **create a new node** with the default value if node is not contained in the current map
     ///
-    /// Return Err when the action will cause cycle in tree
-    pub fn mov(&mut self, node_id: ID, parent_id: Option<ID>) -> Result<(), Error> {
-        let mut deleted = false;
-        let mut contained = false;
-        if let Some(node) = self.map.get(&node_id) {
-            contained = true;
-            if node.deleted {
-                deleted = true;
+    /// A new node, or a node that changes its parent, is appended to the end of the new siblings.
+    /// Moving a node into its current parent keeps its position.
+    ///
+    /// Return Err when the action will cause cycle in tree, or the parent doesn't exist
+    pub fn mov(&mut self, node_id: ID, parent_id: Option<ID>) -> Result<(), Error<ID>>
+    where
+        V: Default,
+    {
+        self.mov_inner(node_id, parent_id, None)
+    }
+
+    /// Move node into new_parent, so that it becomes the `index`-th child of new_parent.
+    /// It will **create a new node** with the default value if node is not contained in the current map
+    ///
+    /// The index is counted after the node is removed from its old position,
+    /// so it must be <= the number of the other children of new_parent.
+    pub fn mov_to(
+        &mut self,
+        node_id: ID,
+        parent_id: Option<ID>,
+        index: usize,
+    ) -> Result<(), Error<ID>>
+    where
+        V: Default,
+    {
+        self.mov_inner(node_id, parent_id, Some(index))
+    }
+
+    fn mov_inner(
+        &mut self,
+        node_id: ID,
+        parent_id: Option<ID>,
+        index: Option<usize>,
+    ) -> Result<(), Error<ID>>
+    where
+        V: Default,
+    {
+        let old_parent = self.map.get(&node_id).map(|node| node.parent);
+        if let Some(parent_id) = parent_id {
+            if !self.map.contains_key(&parent_id) {
+                return Err(Error::ParentNotFound(parent_id));
+            }
+            if old_parent.is_some() && self.is_ancestor_of(node_id, parent_id)? {
+                return Err(Error::CyclicMoveErr);
             }
         }
 
-        // The current implementation doesn't preserve the hierarchy directly,
-        // but it can be inferred.
-        // So we cannot travel the forest cheaply. It needs O(n) to construct the trees first.
-        if parent_id.is_none() {
-            self.map.insert(
-                node_id,
-                TreeNode {
-                    parent: None,
-                    deleted,
-                },
-            );
+        if let Some(index) = index {
+            let mut len = self.children_len(parent_id);
+            if old_parent == Some(parent_id) {
+                len -= 1;
+            }
+            if index > len {
+                return Err(Error::IndexOutOfBound { index, len });
+            }
+        } else if old_parent == Some(parent_id) {
             return Ok(());
         }
 
-        let parent_id = parent_id.unwrap();
-        assert!(
-            self.map.contains_key(&parent_id),
-            "Parent id {:?} does not exist.",
-            parent_id
-        );
+        match self.map.get_mut(&node_id) {
+            Some(node) => {
+                node.parent = parent_id;
+            }
+            None => {
+                self.map.insert(
+                    node_id,
+                    TreeNode {
+                        parent: parent_id,
+                        deleted: false,
+                        value: V::default(),
+                        order: 0,
+                    },
+                );
+            }
+        }
 
-        if contained {
-            if self.is_ancestor_of(node_id, parent_id) {
-                return Err(Error::CyclicMoveErr);
+        if let Some(old_parent) = old_parent {
+            if let Some(siblings) = self.children.get_mut(&old_parent) {
+                siblings.remove(self.map[&node_id].order);
+                if siblings.is_empty() {
+                    self.children.remove(&old_parent);
+                }
             }
+        }
+        self.link(node_id, parent_id, index);
 
-            let node = self.map.get_mut(&node_id).unwrap();
-            node.parent = Some(parent_id);
-        } else {
-            self.map.insert(
-                node_id,
-                TreeNode {
-                    parent: Some(parent_id),
-                    deleted: false,
-                },
-            );
+        if let Some(ancestor_index) = &mut self.ancestor_index {
+            ancestor_index.cut(node_id);
+            if let Some(parent_id) = parent_id {
+                ancestor_index.link(node_id, parent_id);
+            }
         }
 
         Ok(())
     }
 
+    /// Insert `node_id` into the children of `parent`. Append it if `index` is `None`.
+    ///
+    /// The node must be contained in the map.
+    fn link(&mut self, node_id: ID, parent: Option<ID>, index: Option<usize>) {
+        let siblings = self.children.entry(parent).or_default();
+        let index = index.unwrap_or(siblings.len());
+        let order = match siblings.insert(index, node_id) {
+            Some(order) => order,
+            None => {
+                siblings.respace();
+                for (order, id) in siblings.entries() {
+                    self.map.get_mut(id).unwrap().order = order;
+                }
+                siblings.insert(index, node_id).unwrap()
+            }
+        };
+        self.map.get_mut(&node_id).unwrap().order = order;
+    }
+
     #[inline(never)]
-    fn is_ancestor_of(&self, maybe_ancestor: ID, node_id: ID) -> bool {
+    fn is_ancestor_of(&mut self, maybe_ancestor: ID, node_id: ID) -> Result<bool, Error<ID>> {
         if maybe_ancestor == node_id {
-            return true;
+            return Ok(true);
+        }
+
+        if let Some(ancestor_index) = &mut self.ancestor_index {
+            return Ok(ancestor_index.is_ancestor(maybe_ancestor, node_id));
         }
 
         let mut node_id = node_id;
-        loop {
-            let node = self.map.get(&node_id).unwrap();
+        // A valid path to the root can't be longer than the number of nodes
+        for _ in 0..self.map.len() {
+            let node = self.map.get(&node_id).ok_or(Error::CorruptedHierarchy)?;
             match node.parent {
-                Some(parent_id) if parent_id == maybe_ancestor => return true,
-                Some(parent_id) if parent_id == node_id => panic!("loop detected"),
+                Some(parent_id) if parent_id == maybe_ancestor => return Ok(true),
                 Some(parent_id) => {
                     node_id = parent_id;
                 }
-                None => return false,
+                None => return Ok(false),
             }
         }
+
+        Err(Error::CorruptedHierarchy)
+    }
+
+    pub fn delete(&mut self, node_id: ID) -> Result<(), Error<ID>> {
+        self.set_deleted(node_id, true)
+    }
+
+    pub fn undo_delete(&mut self, node_id: ID) -> Result<(), Error<ID>> {
+        self.set_deleted(node_id, false)
+    }
+
+    fn set_deleted(&mut self, node_id: ID, deleted: bool) -> Result<(), Error<ID>> {
+        match self.map.get_mut(&node_id) {
+            Some(node) => {
+                node.deleted = deleted;
+                Ok(())
+            }
+            None => Err(Error::NodeNotFound(node_id)),
+        }
+    }
+
+    /// Iterate over the children of the given node in order. Deleted children are included.
+    pub fn children(&self, node_id: ID) -> impl Iterator<Item = ID> + '_ {
+        self.children_of(Some(node_id))
+    }
+
+    /// Iterate over the children of `parent` in order. `None` stands for the roots.
+    pub(crate) fn children_of(&self, parent: Option<ID>) -> impl Iterator<Item = ID> + '_ {
+        self.children
+            .get(&parent)
+            .into_iter()
+            .flat_map(|x| x.iter().copied())
     }
 
-    pub fn delete(&mut self, node_id: ID) {
-        self.map.get_mut(&node_id).unwrap().deleted = true;
+    /// The number of children of `parent`. `None` stands for the roots.
+    pub fn children_len(&self, parent: Option<ID>) -> usize {
+        self.children.get(&parent).map(|x| x.len()).unwrap_or(0)
     }
 
-    pub fn undo_delete(&mut self, node_id: ID) {
-        self.map.get_mut(&node_id).unwrap().deleted = false;
+    /// The `index`-th child of `parent`. `None` stands for the roots.
+    pub fn child_at(&self, parent: Option<ID>, index: usize) -> Option<ID> {
+        self.children.get(&parent)?.get(index).copied()
     }
 
-    pub(crate) fn get(&self, id: &ID) -> Option<&TreeNode<ID>> {
+    /// The number of leading children of `parent` that satisfy `pred`, in O(log n).
+    /// The children satisfying it must come first.
+    pub(crate) fn children_partition_point(
+        &self,
+        parent: Option<ID>,
+        mut pred: impl FnMut(ID) -> bool,
+    ) -> usize {
+        self.children
+            .get(&parent)
+            .map(|x| x.partition_point(|id| pred(*id)))
+            .unwrap_or(0)
+    }
+
+    /// The index of the node among its siblings, including the deleted ones, in O(log n).
+    ///
+    /// Return `None` if the node is not contained in the forest.
+    pub fn index_in_parent(&self, node_id: ID) -> Option<usize> {
+        let node = self.map.get(&node_id)?;
+        self.children.get(&node.parent)?.index_of(node.order)
+    }
+
+    /// Get the parent of the node.
+    ///
+    /// Return `None` if the node is a root or is not contained in the forest.
+    pub fn parent_of(&self, node_id: ID) -> Option<ID> {
+        self.map.get(&node_id).and_then(|x| x.parent)
+    }
+
+    /// Whether the node is contained in the forest. Deleted nodes are still contained.
+    pub fn contains(&self, node_id: ID) -> bool {
+        self.map.contains_key(&node_id)
+    }
+
+    /// Whether the node is deleted. Return false if the node is not contained in the forest.
+    pub fn is_deleted(&self, node_id: ID) -> bool {
+        self.map.get(&node_id).map(|x| x.deleted).unwrap_or(false)
+    }
+
+    /// Iterate over the root nodes in order, including the deleted ones.
+    pub fn roots(&self) -> impl Iterator<Item = ID> + '_ {
+        self.children
+            .get(&None)
+            .into_iter()
+            .flat_map(|x| x.iter().copied())
+    }
+
+    /// The number of nodes in the forest, including the deleted ones.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Iterate over all the nodes in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (&ID, &TreeNode<ID, V>)> + '_ {
+        self.map.iter()
+    }
+
+    /// Get the node by id. Return `None` if it's not contained in the forest.
+    pub fn get(&self, id: &ID) -> Option<&TreeNode<ID, V>> {
         self.map.get(id)
     }
+
+    /// Get the value of the node. Return `None` if it's not contained in the forest.
+    pub fn get_value(&self, node_id: ID) -> Option<&V> {
+        self.map.get(&node_id).map(|x| &x.value)
+    }
+
+    /// Replace the value of the node and return the old one.
+    pub fn set_value(&mut self, node_id: ID, value: V) -> Result<V, Error<ID>> {
+        self.map
+            .get_mut(&node_id)
+            .map(|x| std::mem::replace(&mut x.value, value))
+            .ok_or(Error::NodeNotFound(node_id))
+    }
 }
 
-impl<ID: IdTrait> Default for Forest<ID> {
+impl<ID: IdTrait, V> Default for Forest<ID, V> {
     fn default() -> Self {
         Self::new()
     }
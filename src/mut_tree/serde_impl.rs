@@ -0,0 +1,40 @@
+//! Serialized in the same format as [`crate::Forest`]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{Forest, IdTrait, TreeNode};
+use crate::tree::serde_impl::{validate, SerNode, SerNodeRef};
+
+impl<ID: IdTrait + Serialize, V: Serialize> Serialize for Forest<ID, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.children.values().flat_map(|x| x.iter()).map(|id| {
+            let node = &self.map[id];
+            SerNodeRef {
+                id: *id,
+                parent: node.parent,
+                deleted: node.deleted,
+                value: &node.value,
+            }
+        }))
+    }
+}
+
+impl<'de, ID: IdTrait + Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for Forest<ID, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let nodes: Vec<SerNode<ID, V>> = Vec::deserialize(deserializer)?;
+        validate::<_, _, D::Error>(&nodes)?;
+        let mut forest = Forest::new();
+        for node in nodes {
+            forest.map.insert(
+                node.id,
+                TreeNode {
+                    parent: node.parent,
+                    deleted: node.deleted,
+                    value: node.value,
+                    order: 0,
+                },
+            );
+            forest.link(node.id, node.parent, None);
+        }
+        Ok(forest)
+    }
+}
@@ -0,0 +1,366 @@
+//! A persistent order-statistic tree that keeps the children of a node in order.
+//!
+//! Every element has a key, and the elements are sorted by their keys. An element inserted
+//! between two others gets a key between theirs, so the owner can store the key and find
+//! the index of the element in O(log n). When there's no room left between two keys, the
+//! keys are spread evenly again by [`OrderTree::respace`], which takes O(n log n).
+//!
+//! It's a treap whose priorities are derived from the keys. Cloning it is O(1), and an
+//! update only copies the path to the changed element.
+use std::{fmt::Debug, sync::Arc};
+
+pub(crate) type Key = u64;
+
+/// The greatest key. The keys fit in 63 bits, so the owner can store a flag next to a key.
+pub(crate) const MAX_KEY: Key = Key::MAX >> 1;
+
+/// The gap between the keys of the appended elements, and between the respaced keys
+const STEP: Key = 1 << 32;
+
+type Link<T> = Option<Arc<Node<T>>>;
+
+#[derive(Clone)]
+struct Node<T> {
+    key: Key,
+    value: T,
+    /// The number of elements in the subtree
+    len: usize,
+    left: Link<T>,
+    right: Link<T>,
+}
+
+impl<T> Node<T> {
+    fn new(key: Key, value: T, left: Link<T>, right: Link<T>) -> Self {
+        let mut node = Self {
+            key,
+            value,
+            len: 1,
+            left,
+            right,
+        };
+        node.update_len();
+        node
+    }
+
+    fn update_len(&mut self) {
+        self.len = 1 + len(&self.left) + len(&self.right);
+    }
+}
+
+fn len<T>(link: &Link<T>) -> usize {
+    link.as_ref().map(|x| x.len).unwrap_or(0)
+}
+
+/// A fixed pseudo-random priority, so the shape of the tree doesn't depend on the order of updates
+fn priority(key: Key) -> u64 {
+    // the finalizer of splitmix64
+    let mut x = key;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[derive(Clone)]
+pub(crate) struct OrderTree<T> {
+    root: Link<T>,
+}
+
+impl<T> Default for OrderTree<T> {
+    fn default() -> Self {
+        Self { root: None }
+    }
+}
+
+impl<T> OrderTree<T> {
+    pub fn len(&self) -> usize {
+        len(&self.root)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    fn node_at(&self, mut index: usize) -> Option<&Node<T>> {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            let left = len(&node.left);
+            if index < left {
+                link = &node.left;
+            } else if index == left {
+                return Some(node);
+            } else {
+                index -= left + 1;
+                link = &node.right;
+            }
+        }
+        None
+    }
+
+    /// The `index`-th element
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.node_at(index).map(|x| &x.value)
+    }
+
+    /// The index of the element with the key
+    pub fn index_of(&self, key: Key) -> Option<usize> {
+        let mut link = &self.root;
+        let mut index = 0;
+        while let Some(node) = link {
+            if key < node.key {
+                link = &node.left;
+            } else {
+                let left = len(&node.left);
+                if key == node.key {
+                    return Some(index + left);
+                }
+                index += left + 1;
+                link = &node.right;
+            }
+        }
+        None
+    }
+
+    /// The number of leading elements that satisfy `pred`, like [`slice::partition_point`].
+    /// The elements satisfying it must come first.
+    pub fn partition_point(&self, mut pred: impl FnMut(&T) -> bool) -> usize {
+        let mut link = &self.root;
+        let mut index = 0;
+        while let Some(node) = link {
+            if pred(&node.value) {
+                index += len(&node.left) + 1;
+                link = &node.right;
+            } else {
+                link = &node.left;
+            }
+        }
+        index
+    }
+
+    /// Iterate over the keys and the elements in order
+    pub fn entries(&self) -> Iter<'_, T> {
+        let mut iter = Iter { stack: Vec::new() };
+        iter.push_left(&self.root);
+        iter
+    }
+
+    /// Iterate over the elements in order
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        self.entries().map(|(_, x)| x)
+    }
+}
+
+impl<T: Clone> OrderTree<T> {
+    /// Insert the value so that it becomes the `index`-th element, and return its key.
+    ///
+    /// Return `None` and insert nothing if there's no room for a key at the index.
+    /// The keys should be respaced then.
+    pub fn insert(&mut self, index: usize, value: T) -> Option<Key> {
+        let lower = index.checked_sub(1).map(|i| self.node_at(i).unwrap().key);
+        let upper = self.node_at(index).map(|x| x.key);
+        let key = match (lower, upper) {
+            (None, None) => MAX_KEY / 2,
+            (Some(lower), None) => Some(lower + STEP).filter(|x| *x <= MAX_KEY)?,
+            (None, Some(upper)) => upper.checked_sub(STEP)?,
+            (Some(lower), Some(upper)) if upper - lower >= 2 => lower + (upper - lower) / 2,
+            (Some(_), Some(_)) => return None,
+        };
+        insert(&mut self.root, key, value);
+        Some(key)
+    }
+
+    /// Remove the element with the key
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        remove(&mut self.root, key)
+    }
+
+    /// Give the elements evenly spaced keys around the middle of the key space.
+    ///
+    /// The new keys can be read by [`OrderTree::entries`].
+    pub fn respace(&mut self) {
+        let values: Vec<T> = self.iter().cloned().collect();
+        let step = STEP.min(MAX_KEY / (values.len() as Key + 2));
+        let mut key = MAX_KEY / 2 - step * (values.len() as Key / 2);
+        self.root = None;
+        for value in values {
+            insert(&mut self.root, key, value);
+            key += step;
+        }
+    }
+}
+
+fn insert<T: Clone>(link: &mut Link<T>, key: Key, value: T) {
+    match link {
+        Some(node) if priority(node.key) > priority(key) => {
+            let node = Arc::make_mut(node);
+            node.len += 1;
+            if key < node.key {
+                insert(&mut node.left, key, value);
+            } else {
+                insert(&mut node.right, key, value);
+            }
+        }
+        _ => {
+            let (left, right) = split(link.take(), key);
+            *link = Some(Arc::new(Node::new(key, value, left, right)));
+        }
+    }
+}
+
+/// Split into the elements with smaller keys and the ones with greater keys
+fn split<T: Clone>(link: Link<T>, key: Key) -> (Link<T>, Link<T>) {
+    let Some(mut node) = link else {
+        return (None, None);
+    };
+    let inner = Arc::make_mut(&mut node);
+    if inner.key < key {
+        let (left, right) = split(inner.right.take(), key);
+        inner.right = left;
+        inner.update_len();
+        (Some(node), right)
+    } else {
+        let (left, right) = split(inner.left.take(), key);
+        inner.left = right;
+        inner.update_len();
+        (left, Some(node))
+    }
+}
+
+/// Join two trees. The keys of `a` must be smaller than the keys of `b`.
+fn merge<T: Clone>(a: Link<T>, b: Link<T>) -> Link<T> {
+    match (a, b) {
+        (None, x) | (x, None) => x,
+        (Some(mut a), Some(mut b)) => {
+            if priority(a.key) > priority(b.key) {
+                let inner = Arc::make_mut(&mut a);
+                inner.right = merge(inner.right.take(), Some(b));
+                inner.update_len();
+                Some(a)
+            } else {
+                let inner = Arc::make_mut(&mut b);
+                inner.left = merge(Some(a), inner.left.take());
+                inner.update_len();
+                Some(b)
+            }
+        }
+    }
+}
+
+fn remove<T: Clone>(link: &mut Link<T>, key: Key) -> Option<T> {
+    let node = link.as_mut()?;
+    if node.key == key {
+        let node = link.take().unwrap();
+        let Node {
+            value, left, right, ..
+        } = Arc::try_unwrap(node).unwrap_or_else(|x| (*x).clone());
+        *link = merge(left, right);
+        return Some(value);
+    }
+
+    let node = Arc::make_mut(node);
+    let value = if key < node.key {
+        remove(&mut node.left, key)
+    } else {
+        remove(&mut node.right, key)
+    }?;
+    node.len -= 1;
+    Some(value)
+}
+
+pub(crate) struct Iter<'a, T> {
+    stack: Vec<&'a Node<T>>,
+}
+
+impl<'a, T> Iter<'a, T> {
+    fn push_left(&mut self, mut link: &'a Link<T>) {
+        while let Some(node) = link {
+            self.stack.push(node);
+            link = &node.left;
+        }
+    }
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Key, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.push_left(&node.right);
+        Some((node.key, &node.value))
+    }
+}
+
+/// Two trees are equal if they have the same elements in the same order, whatever the keys
+impl<T: PartialEq> PartialEq for OrderTree<T> {
+    fn eq(&self, other: &Self) -> bool {
+        let same_root = match (&self.root, &other.root) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        same_root || (self.len() == other.len() && self.iter().eq(other.iter()))
+    }
+}
+
+impl<T: Eq> Eq for OrderTree<T> {}
+
+impl<T: Debug> Debug for OrderTree<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn same_as_vec() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut tree: OrderTree<usize> = OrderTree::default();
+        let mut keys: Vec<Key> = Vec::new();
+        let mut vec: Vec<usize> = Vec::new();
+        for i in 0..3000 {
+            if !vec.is_empty() && rng.gen_bool(0.3) {
+                let index = rng.gen_range(0..vec.len());
+                assert_eq!(tree.remove(keys.remove(index)), Some(vec.remove(index)));
+                continue;
+            }
+
+            // insert at the same spot now and then to run out of room
+            let index = if rng.gen_bool(0.5) {
+                vec.len() / 2
+            } else {
+                rng.gen_range(0..=vec.len())
+            };
+            let old = tree.clone();
+            let key = match tree.insert(index, i) {
+                Some(key) => key,
+                None => {
+                    tree.respace();
+                    keys = tree.entries().map(|(key, _)| key).collect();
+                    tree.insert(index, i).unwrap()
+                }
+            };
+            assert_eq!(old.len(), vec.len());
+            keys.insert(index, key);
+            vec.insert(index, i);
+            assert_eq!(tree.len(), vec.len());
+            assert_eq!(tree.get(index), Some(&i));
+            assert_eq!(tree.index_of(key), Some(index));
+        }
+
+        assert_eq!(tree.iter().copied().collect::<Vec<_>>(), vec);
+        for k in [0, 1, vec.len() / 2, vec.len()] {
+            let prefix = &vec[..k];
+            assert_eq!(tree.partition_point(|x| prefix.contains(x)), k);
+        }
+        assert_eq!(tree.entries().map(|(key, _)| key).collect::<Vec<_>>(), keys);
+        assert!(keys.windows(2).all(|x| x[0] < x[1]));
+        assert!(keys.iter().all(|x| *x <= MAX_KEY));
+        let mut respaced = tree.clone();
+        respaced.respace();
+        assert_eq!(respaced, tree);
+        assert_eq!(tree.remove(Key::MAX), None);
+    }
+}
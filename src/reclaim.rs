@@ -0,0 +1,208 @@
+//! Deferred reclamation of large persistent structures.
+//!
+//! Dropping a long history of forests takes about as long as building it, because
+//! every version has to release its share of the nodes. [`Reclaimer`] moves that
+//! work to a background thread, so the caller doesn't wait for it unless the queue
+//! of pending values is full.
+//!
+//! # Example
+//!
+//! ```
+//! use movable_tree::{reclaim::Deferred, History};
+//! let mut history: Deferred<History<usize>> = Deferred::new(History::new());
+//! for i in 0..1000 {
+//!     history.mov(i, None).unwrap();
+//! }
+//! // the versions are dropped by the background thread
+//! drop(history);
+//! ```
+use std::{
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    sync::{
+        mpsc::{sync_channel, SendError, SyncSender},
+        OnceLock,
+    },
+    thread,
+};
+
+enum Message {
+    Drop(#[allow(unused)] Box<dyn Send>),
+    Flush(SyncSender<()>),
+}
+
+/// A handle to a background thread that drops the values sent to it.
+///
+/// The queue holds at most `capacity` values. When it's full, [`Reclaimer::defer`] waits
+/// until the thread takes the next one, so at most `capacity + 1` values are waiting or
+/// being dropped at a time. The bound counts values, not bytes, so the pending values can
+/// hold as much memory as `capacity + 1` of the largest values deferred. A caller that finds
+/// the queue full waits for a drop in progress, which can take as long as dropping that
+/// value in place.
+///
+/// Cloning the handle shares the same thread. The thread exits after all the handles are dropped.
+#[derive(Clone)]
+pub struct Reclaimer {
+    sender: SyncSender<Message>,
+}
+
+impl Debug for Reclaimer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reclaimer").finish()
+    }
+}
+
+/// The number of pending values of the global reclaimer, whatever their size
+const DEFAULT_CAPACITY: usize = 64;
+
+impl Reclaimer {
+    /// Spawn a thread that reclaims at most `capacity` pending values.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, receiver) = sync_channel(capacity);
+        thread::Builder::new()
+            .name("movable-tree-reclaimer".into())
+            .spawn(move || {
+                for msg in receiver {
+                    match msg {
+                        Message::Drop(value) => drop(value),
+                        Message::Flush(done) => {
+                            done.send(()).unwrap_or_default();
+                        }
+                    }
+                }
+            })
+            .unwrap();
+        Self { sender }
+    }
+
+    /// The reclaimer shared by the whole process
+    pub fn global() -> &'static Reclaimer {
+        static GLOBAL: OnceLock<Reclaimer> = OnceLock::new();
+        GLOBAL.get_or_init(|| Reclaimer::new(DEFAULT_CAPACITY))
+    }
+
+    /// Drop the value on the background thread. Block while the queue is full.
+    ///
+    /// If the thread is gone, because a drop panicked, the value is dropped in place.
+    pub fn defer<T: Send + 'static>(&self, value: T) {
+        if let Err(SendError(msg)) = self.sender.send(Message::Drop(Box::new(value))) {
+            drop(msg);
+        }
+    }
+
+    /// Block until all the values deferred before are dropped.
+    pub fn flush(&self) {
+        let (sender, receiver) = sync_channel(1);
+        if self.sender.send(Message::Flush(sender)).is_ok() {
+            receiver.recv().unwrap_or_default();
+        }
+    }
+}
+
+/// Hand `value` to the global reclaimer
+pub fn defer_drop<T: Send + 'static>(value: T) {
+    Reclaimer::global().defer(value)
+}
+
+/// A wrapper that hands its value to a [`Reclaimer`] when it's dropped.
+///
+/// It can wrap anything that is expensive to drop, e.g. a [`crate::History`] or a `Vec<Forest>`.
+pub struct Deferred<T: Send + 'static> {
+    value: Option<T>,
+    reclaimer: Reclaimer,
+}
+
+impl<T: Send + 'static> Deferred<T> {
+    /// Wrap the value. It will be dropped by the global reclaimer.
+    pub fn new(value: T) -> Self {
+        Self::with_reclaimer(value, Reclaimer::global().clone())
+    }
+
+    pub fn with_reclaimer(value: T, reclaimer: Reclaimer) -> Self {
+        Self {
+            value: Some(value),
+            reclaimer,
+        }
+    }
+
+    /// Take the value back. It won't be dropped in the background.
+    pub fn into_inner(mut self) -> T {
+        self.value.take().unwrap()
+    }
+}
+
+impl<T: Send + 'static> Deref for Deferred<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<T: Send + 'static> DerefMut for Deferred<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<T: Send + 'static + Debug> Debug for Deferred<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Deferred").field(&self.value).finish()
+    }
+}
+
+impl<T: Send + 'static> Drop for Deferred<T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            self.reclaimer.defer(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    struct Probe(Arc<Mutex<Vec<thread::ThreadId>>>);
+
+    impl Drop for Probe {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().push(thread::current().id());
+        }
+    }
+
+    #[test]
+    fn drop_in_background() {
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let reclaimer = Reclaimer::new(4);
+        reclaimer.defer(Probe(dropped.clone()));
+        drop(Deferred::with_reclaimer(
+            Probe(dropped.clone()),
+            reclaimer.clone(),
+        ));
+        let kept = Deferred::with_reclaimer(Probe(dropped.clone()), reclaimer.clone());
+        let kept = kept.into_inner();
+        reclaimer.flush();
+        let threads = dropped.lock().unwrap().clone();
+        assert_eq!(threads.len(), 2);
+        assert!(threads.iter().all(|x| *x != thread::current().id()));
+        drop(kept);
+        assert_eq!(dropped.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn bounded_queue() {
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let reclaimer = Reclaimer::new(0);
+        for _ in 0..100 {
+            reclaimer.defer(Probe(dropped.clone()));
+        }
+        reclaimer.flush();
+        // the caller waits for the full queue instead of dropping the values itself
+        let threads = dropped.lock().unwrap().clone();
+        assert_eq!(threads.len(), 100);
+        assert!(threads.iter().all(|x| *x != thread::current().id()));
+    }
+}
@@ -0,0 +1,481 @@
+//! An append-only log of the ops of a replica on disk.
+//!
+//! The log is a directory of segment files. Each segment starts with a magic header and
+//! holds a sequence of records, one for each saved batch of ops:
+//!
+//! ```log
+//! segment: magic (8 bytes) record*
+//! record:  len (u32 LE) crc32 (u32 LE) payload (len bytes)
+//! ```
+//!
+//! The payload is the batch encoded by [`crate::crdt::encode_ops`], and the checksum is
+//! the CRC-32 (IEEE) of the payload. A record is flushed to the disk before
+//! [`Storage::save`] returns, so a saved batch survives a crash as a whole or not at all.
+//!
+//! A crash in the middle of a write leaves a torn record at the end of the last segment.
+//! It runs past the end of the segment, or, if the length was written before the payload
+//! reached the disk, it ends at the end of the segment with a payload that fails the
+//! checksum or can't be decoded. It's truncated when the log is opened. Any other broken
+//! record, e.g. a checksum mismatch followed by valid records or a torn record in an older
+//! segment, can't be caused by a crash, so it's reported as [`StorageError::Corrupted`] and
+//! nothing is truncated.
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    crdt::{decode_ops, encode_ops, Client, Crdt, Strategy, SyncError, TreeBackend},
+    version_vector::VersionVector,
+};
+
+const MAGIC: &[u8; 8] = b"mtoplog1";
+const RECORD_HEADER_LEN: usize = 8;
+
+/// The default limit of [`Storage::set_max_segment_len`]
+pub const DEFAULT_MAX_SEGMENT_LEN: u64 = 64 << 20;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    /// A record is broken for a reason other than a torn write, at the byte offset in the
+    /// segment
+    Corrupted {
+        segment: u64,
+        offset: u64,
+    },
+    /// The ops in the log can't be imported, or the ops to save have been compacted
+    Sync(SyncError),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Io(err) => write!(f, "io error: {}", err),
+            StorageError::Corrupted { segment, offset } => {
+                write!(f, "segment {} is corrupted at offset {}", segment, offset)
+            }
+            StorageError::Sync(err) => write!(f, "invalid ops: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+impl From<SyncError> for StorageError {
+    fn from(err: SyncError) -> Self {
+        StorageError::Sync(err)
+    }
+}
+
+/// The on-disk op log of a replica.
+///
+/// # Example
+///
+/// ```no_run
+/// use movable_tree::{crdt_undo::Crdt, storage::Storage};
+/// let (mut storage, mut crdt): (Storage, Crdt) = Storage::open("doc", 1).unwrap();
+/// crdt.new_node(None).unwrap();
+/// storage.save(&crdt).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Storage {
+    dir: PathBuf,
+    file: File,
+    /// The index of the last segment
+    segment: u64,
+    /// The length of the last segment
+    len: u64,
+    max_segment_len: u64,
+    /// The ops that have been written
+    saved: VersionVector,
+}
+
+impl Storage {
+    /// Open the log in `dir`, creating it if it doesn't exist, and rebuild the replica of
+    /// `client` from the ops in it.
+    ///
+    /// A torn record at the end of the log is truncated.
+    pub fn open<B: TreeBackend, S: Strategy<B>>(
+        dir: impl AsRef<Path>,
+        client: Client,
+    ) -> Result<(Self, Crdt<B, S>), StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            if let Some(index) = name
+                .to_str()
+                .and_then(|x| x.strip_suffix(".seg"))
+                .and_then(|x| u64::from_str_radix(x, 16).ok())
+            {
+                segments.push(index);
+            }
+        }
+        segments.sort_unstable();
+
+        let mut crdt = Crdt::new(client);
+        // the whole log is read already, so the batch is not limited
+        crdt.set_max_batch_len(usize::MAX);
+        let mut ops = Vec::new();
+        let mut last = None;
+        for (i, &index) in segments.iter().enumerate() {
+            let path = segment_path(&dir, index);
+            let mut bytes = Vec::new();
+            File::open(&path)?.read_to_end(&mut bytes)?;
+            let (end, tail) = read_records(&bytes, &mut ops);
+            match tail {
+                SegmentEnd::Complete => {}
+                SegmentEnd::Torn if i + 1 == segments.len() => {
+                    let file = OpenOptions::new().write(true).open(&path)?;
+                    file.set_len(end as u64)?;
+                    file.sync_all()?;
+                }
+                SegmentEnd::Torn | SegmentEnd::Corrupted => {
+                    return Err(StorageError::Corrupted {
+                        segment: index,
+                        offset: end as u64,
+                    });
+                }
+            }
+            last = Some((index, end as u64));
+        }
+        crdt.import_ops(ops)?;
+        crdt.set_max_batch_len(crate::crdt::DEFAULT_MAX_BATCH_LEN);
+
+        let (segment, file, len) = match last {
+            Some((index, len)) if len >= MAGIC.len() as u64 => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .open(segment_path(&dir, index))?;
+                (index, file, len)
+            }
+            // the header of the segment is torn
+            Some((index, _)) => (index, create_segment(&dir, index)?, MAGIC.len() as u64),
+            None => (0, create_segment(&dir, 0)?, MAGIC.len() as u64),
+        };
+        let storage = Storage {
+            dir,
+            file,
+            segment,
+            len,
+            max_segment_len: DEFAULT_MAX_SEGMENT_LEN,
+            saved: crdt.version(),
+        };
+        Ok((storage, crdt))
+    }
+
+    /// Start a new segment when the last one reaches `len` bytes
+    pub fn set_max_segment_len(&mut self, len: u64) {
+        self.max_segment_len = len;
+    }
+
+    /// Write the ops of `crdt` that have not been saved as one batch, and flush them to the
+    /// disk. Return the number of written ops.
+    ///
+    /// The ops waiting for their dependencies are not saved. The ops must be saved before
+    /// they are compacted by [`Crdt::compact`].
+    pub fn save<B: TreeBackend, S: Strategy<B>>(
+        &mut self,
+        crdt: &Crdt<B, S>,
+    ) -> Result<usize, StorageError> {
+        let ops = crdt.export_ops(&self.saved)?;
+        if ops.is_empty() {
+            return Ok(0);
+        }
+
+        let payload = encode_ops(&ops);
+        if self.len >= self.max_segment_len {
+            self.segment += 1;
+            self.file = create_segment(&self.dir, self.segment)?;
+            self.len = MAGIC.len() as u64;
+        }
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        if let Err(err) = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data())
+        {
+            // drop the partial record, so the next records are not written after it
+            self.file.set_len(self.len).ok();
+            return Err(err.into());
+        }
+        self.len += record.len() as u64;
+        self.saved = crdt.version();
+        Ok(ops.len())
+    }
+
+    /// The number of saved ops of each client
+    pub fn saved_version(&self) -> &VersionVector {
+        &self.saved
+    }
+}
+
+fn segment_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{:016x}.seg", index))
+}
+
+/// Create an empty segment, and make sure its entry in the directory is on the disk
+fn create_segment(dir: &Path, index: u64) -> io::Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(segment_path(dir, index))?;
+    file.write_all(MAGIC)?;
+    file.sync_all()?;
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    OpenOptions::new()
+        .append(true)
+        .open(segment_path(dir, index))
+}
+
+/// What follows the valid records of a segment
+#[derive(Debug, PartialEq, Eq)]
+enum SegmentEnd {
+    /// Nothing, the segment is valid up to its end
+    Complete,
+    /// A record that runs past the end of the segment, or a broken record that ends at it
+    Torn,
+    /// A broken record followed by other bytes
+    Corrupted,
+}
+
+/// Decode the records of a segment into `ops`. Return the end of the valid records, and
+/// what follows them.
+fn read_records(bytes: &[u8], ops: &mut Vec<crate::crdt::Op>) -> (usize, SegmentEnd) {
+    if bytes.len() < MAGIC.len() {
+        return (0, SegmentEnd::Torn);
+    }
+    if &bytes[..MAGIC.len()] != MAGIC {
+        return (0, SegmentEnd::Corrupted);
+    }
+
+    let mut offset = MAGIC.len();
+    while offset < bytes.len() {
+        let Some(header) = bytes.get(offset..offset + RECORD_HEADER_LEN) else {
+            return (offset, SegmentEnd::Torn);
+        };
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let start = offset + RECORD_HEADER_LEN;
+        let Some(payload) = bytes.get(start..start + len) else {
+            return (offset, SegmentEnd::Torn);
+        };
+        // the payload of the last record may not have reached the disk before a crash
+        let broken = if start + len == bytes.len() {
+            SegmentEnd::Torn
+        } else {
+            SegmentEnd::Corrupted
+        };
+        if crc32(payload) != checksum {
+            return (offset, broken);
+        }
+        match decode_ops(payload) {
+            Ok(batch) => ops.extend(batch),
+            Err(_) => return (offset, broken),
+        }
+        offset = start + len;
+    }
+    (offset, SegmentEnd::Complete)
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 with the IEEE polynomial, as used by zlib and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc = CRC_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, mut a): (Storage, crate::crdt_undo::Crdt) =
+            Storage::open(dir.path(), 1).unwrap();
+        let mut b = crate::crdt_undo::Crdt::new(2);
+        let root = a.new_node(None).unwrap();
+        let child = a.new_node(Some(root)).unwrap();
+        assert_eq!(storage.save(&a).unwrap(), 2);
+        assert_eq!(storage.save(&a).unwrap(), 0);
+        b.merge(&a).unwrap();
+        b.mov(child, None).unwrap();
+        a.merge(&b).unwrap();
+        a.delete(root).unwrap();
+        assert_eq!(storage.save(&a).unwrap(), 2);
+        drop(storage);
+
+        // both CRDTs are rebuilt from the same log
+        let (mut storage, mut c): (Storage, crate::crdt_undo::Crdt) =
+            Storage::open(dir.path(), 1).unwrap();
+        assert_eq!(c.forest(), a.forest());
+        assert_eq!(storage.saved_version(), &a.version());
+        let (_, d): (Storage, crate::crdt_snapshot::Crdt) = Storage::open(dir.path(), 1).unwrap();
+        assert_eq!(d.version(), a.version());
+
+        // the rebuilt replica keeps editing and saving
+        let x = c.new_node(Some(child)).unwrap();
+        storage.save(&c).unwrap();
+        drop(storage);
+        let (_, c): (Storage, crate::crdt_undo::Crdt) = Storage::open(dir.path(), 1).unwrap();
+        assert_eq!(c.forest().parent_of(x), Some(child));
+    }
+
+    #[test]
+    fn torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, mut a): (Storage, crate::crdt_undo::Crdt) =
+            Storage::open(dir.path(), 1).unwrap();
+        let root = a.new_node(None).unwrap();
+        storage.save(&a).unwrap();
+        let saved = a.clone();
+        a.new_node(Some(root)).unwrap();
+        storage.save(&a).unwrap();
+        drop(storage);
+
+        let path = segment_path(dir.path(), 0);
+        let full = fs::read(&path).unwrap();
+        // cut the second record at every byte
+        let first_end = MAGIC.len()
+            + RECORD_HEADER_LEN
+            + encode_ops(&saved.export_ops(&Default::default()).unwrap()).len();
+        for end in first_end..full.len() {
+            fs::write(&path, &full[..end]).unwrap();
+            let (_, c): (Storage, crate::crdt_undo::Crdt) = Storage::open(dir.path(), 1).unwrap();
+            assert_eq!(c.forest(), saved.forest());
+            assert_eq!(fs::metadata(&path).unwrap().len(), first_end as u64);
+        }
+
+        // the lost op is written again after the valid records
+        let (mut storage, mut c): (Storage, crate::crdt_undo::Crdt) =
+            Storage::open(dir.path(), 1).unwrap();
+        c.merge(&a).unwrap();
+        storage.save(&c).unwrap();
+        drop(storage);
+        let (_, c): (Storage, crate::crdt_undo::Crdt) = Storage::open(dir.path(), 1).unwrap();
+        assert_eq!(c.forest(), a.forest());
+
+        // a flipped byte followed by valid records is not a torn write, so no record is dropped
+        let full = fs::read(&path).unwrap();
+        let mut flipped = full.clone();
+        flipped[MAGIC.len() + RECORD_HEADER_LEN] ^= 1;
+        fs::write(&path, &flipped).unwrap();
+        let result: Result<(Storage, crate::crdt_undo::Crdt), _> = Storage::open(dir.path(), 1);
+        assert!(matches!(
+            result,
+            Err(StorageError::Corrupted { segment: 0, .. })
+        ));
+        assert_eq!(fs::read(&path).unwrap(), flipped);
+    }
+
+    #[test]
+    fn torn_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, mut a): (Storage, crate::crdt_undo::Crdt) =
+            Storage::open(dir.path(), 1).unwrap();
+        let root = a.new_node(None).unwrap();
+        storage.save(&a).unwrap();
+        let saved = a.clone();
+        a.new_node(Some(root)).unwrap();
+        storage.save(&a).unwrap();
+        drop(storage);
+
+        // the header of the last record is complete, but its payload never reached the disk
+        let path = segment_path(dir.path(), 0);
+        let full = fs::read(&path).unwrap();
+        let first_end = full.len()
+            - RECORD_HEADER_LEN
+            - encode_ops(&a.export_ops(&saved.version()).unwrap()).len();
+        for fill in [0, 0xFF] {
+            let mut torn = full.clone();
+            torn[first_end + RECORD_HEADER_LEN..].fill(fill);
+            fs::write(&path, &torn).unwrap();
+            let (_, c): (Storage, crate::crdt_undo::Crdt) = Storage::open(dir.path(), 1).unwrap();
+            assert_eq!(c.forest(), saved.forest());
+            assert_eq!(fs::metadata(&path).unwrap().len(), first_end as u64);
+        }
+
+        // the same record in an older segment is corrupted
+        let mut bytes = full.clone();
+        bytes[first_end + RECORD_HEADER_LEN..].fill(0);
+        fs::write(&path, &bytes).unwrap();
+        fs::write(segment_path(dir.path(), 1), MAGIC).unwrap();
+        let result: Result<(Storage, crate::crdt_undo::Crdt), _> = Storage::open(dir.path(), 1);
+        assert!(matches!(
+            result,
+            Err(StorageError::Corrupted { segment: 0, .. })
+        ));
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, mut a): (Storage, crate::crdt_snapshot::Crdt) =
+            Storage::open(dir.path(), 1).unwrap();
+        storage.set_max_segment_len(64);
+        let root = a.new_node(None).unwrap();
+        for _ in 0..10 {
+            a.new_node(Some(root)).unwrap();
+            storage.save(&a).unwrap();
+        }
+        drop(storage);
+        assert!(fs::read_dir(dir.path()).unwrap().count() > 2);
+        let (_, b): (Storage, crate::crdt_snapshot::Crdt) = Storage::open(dir.path(), 1).unwrap();
+        assert_eq!(b.forest(), a.forest());
+
+        // only the last segment can have a torn tail
+        let path = segment_path(dir.path(), 0);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let result: Result<(Storage, crate::crdt_snapshot::Crdt), _> = Storage::open(dir.path(), 1);
+        assert!(matches!(
+            result,
+            Err(StorageError::Corrupted { segment: 0, .. })
+        ));
+    }
+}
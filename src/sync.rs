@@ -0,0 +1,284 @@
+//! A sync protocol between two replicas that doesn't do any IO by itself.
+//!
+//! Each side keeps a [`SyncSession`] for the peer. The session is fed with the messages
+//! from the peer by [`SyncSession::handle_message`], and the messages to send are taken
+//! from [`SyncSession::poll_outgoing`]. The transport may drop, duplicate and reorder the
+//! messages:
+//!
+//! - A [`Message::Version`] tells the peer which ops the sender has. It's sent when the
+//!   session starts, and as the acknowledgement of the ops imported from the peer.
+//! - A [`Message::Ops`] carries the ops the peer misses, as far as the sender knows.
+//!   The ops can arrive in any order, and the duplicated ones are skipped by the CRDT.
+//!
+//! Nothing is resent until [`SyncSession::on_timeout`] is called, so the driver decides
+//! how long to wait for an acknowledgement.
+//!
+//! # Example
+//!
+//! ```
+//! use movable_tree::{crdt_undo::Crdt, sync::SyncSession};
+//! let mut a = Crdt::new(1);
+//! let mut b = Crdt::new(2);
+//! a.new_node(None).unwrap();
+//! let (mut session_a, mut session_b) = (SyncSession::new(), SyncSession::new());
+//! while !session_a.is_synced(&a) || !session_b.is_synced(&b) {
+//!     while let Some(msg) = session_a.poll_outgoing(&a).unwrap() {
+//!         session_b.handle_message(&mut b, msg).unwrap();
+//!     }
+//!     while let Some(msg) = session_b.poll_outgoing(&b).unwrap() {
+//!         session_a.handle_message(&mut a, msg).unwrap();
+//!     }
+//! }
+//! assert_eq!(a.forest(), b.forest());
+//! ```
+use crate::{
+    crdt::{Crdt, Op, Strategy, SyncError, TreeBackend, ID},
+    version_vector::VersionVector,
+    Change,
+};
+
+/// The default limit of [`SyncSession::set_max_message_len`]
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 1 << 16;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Message {
+    /// The number of ops the sender has seen from each client
+    Version(VersionVector),
+    /// Ops the receiver misses
+    Ops(Vec<Op>),
+}
+
+/// The state of syncing a replica with one peer
+#[derive(Debug, Clone)]
+pub struct SyncSession {
+    /// The latest version the peer has reported. The peer has at least these ops
+    peer_version: Option<VersionVector>,
+    /// The ops that have been sent since the last timeout, on top of `peer_version`
+    sent: VersionVector,
+    /// Whether the version should be sent to the peer
+    version_due: bool,
+    max_message_len: usize,
+}
+
+impl Default for SyncSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SyncSession {
+    pub fn new() -> Self {
+        Self {
+            peer_version: None,
+            sent: VersionVector::new(),
+            version_due: true,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+
+    /// Limit the number of ops in a [`Message::Ops`]. More ops are split into messages.
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.max_message_len = len.max(1);
+    }
+
+    /// Handle a message from the peer, and return the changes of the forest.
+    ///
+    /// Return Err if the ops from the peer are invalid. Nothing is imported then.
+    pub fn handle_message<B: TreeBackend, S: Strategy<B>>(
+        &mut self,
+        crdt: &mut Crdt<B, S>,
+        msg: Message,
+    ) -> Result<Vec<Change<ID>>, SyncError> {
+        match msg {
+            Message::Version(version) => {
+                let version_before = crdt.version();
+                // the versions of the peer only grow, so a stale one changes nothing
+                let peer_version = self.peer_version.get_or_insert_with(Default::default);
+                peer_version.merge(&version);
+                // the peer has ops this replica misses, so it may not know this version
+                if !version_before.includes(&version) {
+                    self.version_due = true;
+                }
+                Ok(Vec::new())
+            }
+            Message::Ops(ops) => {
+                let version = crdt.version();
+                let changes = crdt.import_ops(ops)?;
+                if crdt.version() != version {
+                    self.version_due = true;
+                }
+                Ok(changes)
+            }
+        }
+    }
+
+    /// The next message to send to the peer, or `None` if there is nothing to send.
+    ///
+    /// The ops are sent once the version of the peer is known. The local ops created since
+    /// the last call are sent as well. Return Err if the peer misses ops that have been
+    /// compacted.
+    pub fn poll_outgoing<B: TreeBackend, S: Strategy<B>>(
+        &mut self,
+        crdt: &Crdt<B, S>,
+    ) -> Result<Option<Message>, SyncError> {
+        if self.version_due {
+            self.version_due = false;
+            return Ok(Some(Message::Version(crdt.version())));
+        }
+
+        let Some(peer_version) = &self.peer_version else {
+            return Ok(None);
+        };
+        let mut since = peer_version.clone();
+        since.merge(&self.sent);
+        let mut ops = crdt.export_ops(&since)?;
+        if ops.is_empty() {
+            return Ok(None);
+        }
+        // the ops of each client are in order, so a prefix is sent for each client
+        ops.truncate(self.max_message_len);
+        for op in ops.iter() {
+            let client = op.id().client();
+            if op.counter() >= since.get(client) {
+                since.set(client, op.counter() + 1);
+            }
+        }
+        self.sent = since;
+        Ok(Some(Message::Ops(ops)))
+    }
+
+    /// Assume the messages in flight are lost, so the version and the ops the peer hasn't
+    /// acknowledged are sent again.
+    pub fn on_timeout(&mut self) {
+        self.version_due = true;
+        self.sent = VersionVector::new();
+    }
+
+    /// Whether the peer has reported exactly the ops of `crdt`
+    pub fn is_synced<B: TreeBackend, S: Strategy<B>>(&self, crdt: &Crdt<B, S>) -> bool {
+        self.peer_version.as_ref() == Some(&crdt.version())
+    }
+
+    /// The latest version reported by the peer
+    pub fn peer_version(&self) -> Option<&VersionVector> {
+        self.peer_version.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+    use crate::crdt_undo::Crdt;
+
+    /// A channel that drops, duplicates and reorders the messages
+    struct LossyChannel {
+        queue: Vec<Message>,
+    }
+
+    impl LossyChannel {
+        fn send(&mut self, msg: Message, rng: &mut impl Rng) {
+            match rng.gen_range(0..10) {
+                0..=2 => {}
+                3 => {
+                    self.queue.push(msg.clone());
+                    self.queue.push(msg);
+                }
+                _ => self.queue.push(msg),
+            }
+        }
+
+        fn recv(&mut self, rng: &mut impl Rng) -> Option<Message> {
+            if self.queue.is_empty() {
+                return None;
+            }
+            let i = rng.gen_range(0..self.queue.len());
+            Some(self.queue.swap_remove(i))
+        }
+    }
+
+    #[test]
+    fn lossy_channel() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut a = Crdt::new(1);
+        let mut b = Crdt::new(2);
+        let mut nodes = Vec::new();
+        for _ in 0..20 {
+            nodes.push(a.new_node(None).unwrap());
+        }
+        let mut sessions = [SyncSession::new(), SyncSession::new()];
+        for session in sessions.iter_mut() {
+            session.set_max_message_len(7);
+        }
+        let mut channels = [
+            LossyChannel { queue: Vec::new() },
+            LossyChannel { queue: Vec::new() },
+        ];
+
+        let mut rounds = 0;
+        loop {
+            rounds += 1;
+            assert!(rounds < 10_000);
+            // edit both replicas concurrently in the first rounds
+            if rounds < 50 {
+                let crdt = if rng.gen() { &mut a } else { &mut b };
+                let known: Vec<ID> = nodes
+                    .iter()
+                    .copied()
+                    .filter(|x| crdt.forest().contains(*x))
+                    .collect();
+                if let (Some(&x), Some(&y)) = (known.choose(&mut rng), known.choose(&mut rng)) {
+                    crdt.mov(x, Some(y)).unwrap();
+                }
+                nodes.push(crdt.new_node(known.choose(&mut rng).copied()).unwrap());
+            }
+
+            let [session_a, session_b] = &mut sessions;
+            let [to_b, to_a] = &mut channels;
+            while let Some(msg) = session_a.poll_outgoing(&a).unwrap() {
+                to_b.send(msg, &mut rng);
+            }
+            while let Some(msg) = session_b.poll_outgoing(&b).unwrap() {
+                to_a.send(msg, &mut rng);
+            }
+            for _ in 0..rng.gen_range(0..4) {
+                if let Some(msg) = to_b.recv(&mut rng) {
+                    session_b.handle_message(&mut b, msg).unwrap();
+                }
+                if let Some(msg) = to_a.recv(&mut rng) {
+                    session_a.handle_message(&mut a, msg).unwrap();
+                }
+            }
+
+            if to_a.queue.is_empty() && to_b.queue.is_empty() {
+                if rounds >= 50 && session_a.is_synced(&a) && session_b.is_synced(&b) {
+                    break;
+                }
+                session_a.on_timeout();
+                session_b.on_timeout();
+            }
+        }
+
+        assert_eq!(a.version(), b.version());
+        assert_eq!(a.forest(), b.forest());
+        assert_eq!(a.pending_len() + b.pending_len(), 0);
+    }
+
+    #[test]
+    fn behind_baseline() {
+        let mut a = Crdt::new(1);
+        a.new_node(None).unwrap();
+        a.compact(&a.version());
+        let b = Crdt::new(2);
+        let mut session = SyncSession::new();
+        session.poll_outgoing(&a).unwrap();
+        let msg = SyncSession::new().poll_outgoing(&b).unwrap().unwrap();
+        session.handle_message(&mut a, msg).unwrap();
+        assert_eq!(
+            session.poll_outgoing(&a).unwrap_err(),
+            SyncError::BehindBaseline(1)
+        );
+    }
+}
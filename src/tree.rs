@@ -1,136 +1,977 @@
 use im::HashMap as ImHashMap;
-use std::{fmt::Debug, hash::Hash};
+use std::{
+    fmt::{Debug, Display},
+    hash::Hash,
+    sync::Arc,
+};
+
+use crate::{
+    link_cut::LinkCut,
+    order_tree::{Key, OrderTree, MAX_KEY},
+};
+
+mod diff;
+#[cfg(feature = "serde")]
+pub(crate) mod serde_impl;
+mod traverse;
+pub use diff::*;
+pub use traverse::*;
 
 pub trait IdTrait: Hash + Eq + Clone + Copy + Debug {}
 impl<T: Hash + Eq + Clone + Copy + Debug> IdTrait for T {}
 
+/// An immutable forest whose clone is O(1).
 ///
+/// Besides the parent pointer stored in each node, it maintains a persistent
+/// parent -> ordered children index, so the children of a node can be listed
+/// in order without scanning the whole forest.
 ///
+/// Each node carries a value of type `V`. It's stored in the same persistent map
+/// as the hierarchy, so every cloned version keeps its own values with structural sharing.
+///
+/// It keeps a journal of the nodes touched by the edits, so two versions can be
+/// compared without scanning the whole forest. See [`Forest::diff`].
 #[derive(Clone)]
-pub struct Forest<ID> {
-    map: ImHashMap<ID, TreeNode<ID>>,
+pub struct Forest<ID, V = ()> {
+    map: ImHashMap<ID, TreeNode<ID, V>>,
+    /// Ordered children of each node that has children.
+    /// Each node stores its key in the siblings, so it can be found in O(log n).
+    ///
+    /// Deleted nodes are kept in the index, because deletion doesn't change the hierarchy.
+    children: ImHashMap<ID, OrderTree<ID>>,
+    /// Ordered root nodes. They're kept apart from `children`, so moving a root
+    /// doesn't copy an entry of the map.
+    roots: OrderTree<ID>,
+    /// Optional index for the cycle check. See [`Forest::enable_ancestor_index`].
+    ancestor_index: Option<Arc<LinkCut<ID>>>,
+    journal: Journal<ID>,
 }
 
-impl<ID: Hash + PartialEq + Eq> PartialEq for Forest<ID> {
+impl<ID: Hash + Eq + Clone, V: PartialEq> PartialEq for Forest<ID, V> {
     fn eq(&self, other: &Self) -> bool {
-        self.map == other.map
+        self.map == other.map && self.roots == other.roots && self.children == other.children
     }
 }
 
-impl<ID: Hash + PartialEq + Eq + Debug> Debug for Forest<ID> {
+impl<ID: Hash + Eq + Debug, V: Debug> Debug for Forest<ID, V> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Forest").field("map", &self.map).finish()
     }
 }
 
-impl<ID: Hash + PartialEq + Eq> Eq for Forest<ID> {}
+impl<ID: Hash + Eq + Clone, V: Eq> Eq for Forest<ID, V> {}
 
 /// Immutable tree node.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) struct TreeNode<ID> {
+#[derive(Clone, Copy)]
+pub struct TreeNode<ID, V = ()> {
     pub(crate) parent: Option<ID>,
-    pub(crate) deleted: bool,
+    pub(crate) value: V,
+    /// The key of the node in the children of its parent, shifted left by one bit.
+    /// The lowest bit is set if the node is deleted.
+    ///
+    /// Packing the flag into the key keeps the node as small as it is without the key.
+    /// Every version copies a path of nodes in the map, so the size of a node matters.
+    slot: u64,
+}
+
+impl<ID, V> TreeNode<ID, V> {
+    pub(crate) fn new(parent: Option<ID>, deleted: bool, value: V) -> Self {
+        Self {
+            parent,
+            value,
+            slot: deleted as u64,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_deleted(&self) -> bool {
+        self.slot & 1 == 1
+    }
+
+    fn order(&self) -> Key {
+        self.slot >> 1
+    }
+
+    fn set_order(&mut self, order: Key) {
+        debug_assert!(order <= MAX_KEY);
+        self.slot = (order << 1) | (self.slot & 1);
+    }
+
+    fn set_deleted(&mut self, deleted: bool) {
+        self.slot = (self.slot & !1) | deleted as u64;
+    }
+}
+
+/// The keys are ignored, because the same order of siblings can be given by different keys
+impl<ID: PartialEq, V: PartialEq> PartialEq for TreeNode<ID, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.parent == other.parent
+            && self.is_deleted() == other.is_deleted()
+            && self.value == other.value
+    }
 }
 
-#[derive(Debug, Clone)]
-pub enum Error {
+impl<ID: Eq, V: Eq> Eq for TreeNode<ID, V> {}
+
+impl<ID: Debug, V: Debug> Debug for TreeNode<ID, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TreeNode")
+            .field("parent", &self.parent)
+            .field("deleted", &self.is_deleted())
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+impl<ID: Copy, V> TreeNode<ID, V> {
+    /// The parent of the node. `None` if it's a root.
+    #[inline(always)]
+    pub fn parent(&self) -> Option<ID> {
+        self.parent
+    }
+
+    #[inline(always)]
+    pub fn value(&self) -> &V {
+        &self.value
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<ID> {
+    /// The move would make a node its own ancestor
     CyclicMoveErr,
+    /// The new parent is not contained in the forest
+    ParentNotFound(ID),
+    /// The target node is not contained in the forest
+    NodeNotFound(ID),
+    /// The index is greater than the number of the new siblings
+    IndexOutOfBound { index: usize, len: usize },
+    /// The parent pointers form a loop or point to a missing node.
+    /// It can only happen if the forest was built from corrupted data.
+    CorruptedHierarchy,
 }
 
-impl<ID: IdTrait> Forest<ID> {
+impl<ID: Debug> Display for Error<ID> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::CyclicMoveErr => write!(f, "the move would cause a cycle in the tree"),
+            Error::ParentNotFound(id) => write!(f, "parent id {:?} does not exist", id),
+            Error::NodeNotFound(id) => write!(f, "node id {:?} does not exist", id),
+            Error::IndexOutOfBound { index, len } => {
+                write!(f, "index {} is out of bound, the len is {}", index, len)
+            }
+            Error::CorruptedHierarchy => write!(f, "the hierarchy of the forest is corrupted"),
+        }
+    }
+}
+
+impl<ID: Debug> std::error::Error for Error<ID> {}
+
+impl<ID: IdTrait, V: Clone> Forest<ID, V> {
     #[inline(always)]
     pub fn new() -> Self {
         Self {
             map: Default::default(),
+            children: Default::default(),
+            roots: Default::default(),
+            ancestor_index: None,
+            journal: Default::default(),
         }
     }
 
+    /// Maintain a link-cut tree, so the cycle check of each move takes amortized
+    /// O(log n) instead of O(depth). It's useful for deep trees.
+    ///
+    /// Unlike the rest of the forest, the link-cut tree is not persistent. Clones share it
+    /// until one of them moves a node, which copies it in O(n). So it suits a forest that is
+    /// modified in place, rather than one that is cloned for every version. The check of a
+    /// move reads a shared index without restructuring it, so a rejected move copies nothing,
+    /// but the check takes O(depth) in the worst case then.
+    ///
+    /// Return Err if the hierarchy is corrupted.
+    pub fn enable_ancestor_index(&mut self) -> Result<(), Error<ID>> {
+        // every node should be reachable from the roots, through the children of its parent
+        let consistent = self
+            .children
+            .iter()
+            .map(|(parent, children)| (Some(*parent), children))
+            .chain([(None, &self.roots)])
+            .all(|(parent, children)| {
+                children
+                    .iter()
+                    .all(|x| self.map.get(x).map(|x| x.parent) == Some(parent))
+            });
+        if !consistent || self.pre_order(None).count() != self.map.len() {
+            return Err(Error::CorruptedHierarchy);
+        }
+
+        self.ancestor_index = Some(Arc::new(LinkCut::from_parents(
+            self.map.iter().map(|(id, node)| (*id, node.parent)),
+        )));
+        Ok(())
+    }
+
+    /// Drop the index built by [`Forest::enable_ancestor_index`]
+    pub fn disable_ancestor_index(&mut self) {
+        self.ancestor_index = None;
+    }
+
     /// Move node into new_parent.
-    /// It will **create a new node** if node is not contained in the current map
+    /// It will **create a new node** with the default value if node is not contained in the current map
     ///
-    /// Return Err when the action will cause cycle in tree
-    pub fn mov(&mut self, node_id: ID, parent_id: Option<ID>) -> Result<(), Error> {
-        let mut deleted = false;
-        if let Some(node) = self.map.get(&node_id) {
-            if node.deleted {
-                deleted = true;
-            }
+    /// A new node, or a node that changes its parent, is appended to the end of the new siblings.
+    /// Moving a node into its current parent keeps its position.
+    ///
+    /// Return Err when the action will cause cycle in tree, or the parent doesn't exist
+    pub fn mov(&mut self, node_id: ID, parent_id: Option<ID>) -> Result<(), Error<ID>>
+    where
+        V: Default,
+    {
+        self.mov_inner(node_id, parent_id, None)
+    }
+
+    /// Move node into new_parent, so that it becomes the `index`-th child of new_parent.
+    /// It will **create a new node** with the default value if node is not contained in the current map
+    ///
+    /// The index is counted after the node is removed from its old position,
+    /// so it must be <= the number of the other children of new_parent.
+    pub fn mov_to(
+        &mut self,
+        node_id: ID,
+        parent_id: Option<ID>,
+        index: usize,
+    ) -> Result<(), Error<ID>>
+    where
+        V: Default,
+    {
+        self.mov_inner(node_id, parent_id, Some(index))
+    }
+
+    /// Move node to be the previous sibling of `sibling`
+    pub fn move_before(&mut self, node_id: ID, sibling: ID) -> Result<(), Error<ID>>
+    where
+        V: Default,
+    {
+        let (parent, index) = self.position_without(sibling, node_id)?;
+        self.mov_inner(node_id, parent, Some(index))
+    }
+
+    /// Move node to be the next sibling of `sibling`
+    pub fn move_after(&mut self, node_id: ID, sibling: ID) -> Result<(), Error<ID>>
+    where
+        V: Default,
+    {
+        let (parent, index) = self.position_without(sibling, node_id)?;
+        self.mov_inner(node_id, parent, Some(index + 1))
+    }
+
+    /// The parent of `sibling` and its index after `node_id` is removed from the siblings
+    fn position_without(&self, sibling: ID, node_id: ID) -> Result<(Option<ID>, usize), Error<ID>> {
+        if sibling == node_id {
+            return Err(Error::CyclicMoveErr);
         }
 
-        // The current implementation doesn't preserve the hierarchy directly,
-        // but it can be inferred.
-        // So we cannot travel the forest cheaply. It needs O(n) to construct the trees first.
-        if parent_id.is_none() {
-            self.map.insert(
-                node_id,
-                TreeNode {
-                    parent: None,
-                    deleted,
-                },
-            );
-            return Ok(());
+        let parent = self
+            .map
+            .get(&sibling)
+            .ok_or(Error::NodeNotFound(sibling))?
+            .parent;
+        let mut index = self.index_in_parent(sibling).unwrap();
+        if self.contains(node_id)
+            && self.parent_of(node_id) == parent
+            && self.index_in_parent(node_id).unwrap() < index
+        {
+            index -= 1;
         }
 
-        let parent_id = parent_id.unwrap();
-        assert!(
-            self.map.contains_key(&parent_id),
-            "Parent id {:?} does not exist.",
-            parent_id
-        );
-        if self.map.contains_key(&node_id) {
-            if self.is_ancestor_of(node_id, parent_id) {
+        Ok((parent, index))
+    }
+
+    fn mov_inner(
+        &mut self,
+        node_id: ID,
+        parent_id: Option<ID>,
+        index: Option<usize>,
+    ) -> Result<(), Error<ID>>
+    where
+        V: Default,
+    {
+        let old_parent = self.map.get(&node_id).map(|node| node.parent);
+        if let Some(parent_id) = parent_id {
+            if !self.map.contains_key(&parent_id) {
+                return Err(Error::ParentNotFound(parent_id));
+            }
+            if old_parent.is_some() && self.is_ancestor_of(node_id, parent_id)? {
                 return Err(Error::CyclicMoveErr);
             }
+        }
 
-            let node = self.map.get_mut(&node_id).unwrap();
-            node.parent = Some(parent_id);
-        } else {
-            self.map.insert(
-                node_id,
-                TreeNode {
-                    parent: Some(parent_id),
-                    deleted: false,
-                },
-            );
+        if let Some(index) = index {
+            let mut len = self.children_len(parent_id);
+            if old_parent == Some(parent_id) {
+                len -= 1;
+            }
+            if index > len {
+                return Err(Error::IndexOutOfBound { index, len });
+            }
+        } else if old_parent == Some(parent_id) {
+            return Ok(());
+        }
+
+        match self.map.get_mut(&node_id) {
+            Some(node) => {
+                node.parent = parent_id;
+            }
+            None => {
+                self.map
+                    .insert(node_id, TreeNode::new(parent_id, false, V::default()));
+            }
         }
 
+        if let Some(old_parent) = old_parent {
+            self.unlink(node_id, old_parent);
+        }
+        self.link(node_id, parent_id, index);
+        if let Some(ancestor_index) = &mut self.ancestor_index {
+            let ancestor_index = Arc::make_mut(ancestor_index);
+            ancestor_index.cut(node_id);
+            if let Some(parent_id) = parent_id {
+                ancestor_index.link(node_id, parent_id);
+            }
+        }
         Ok(())
     }
 
-    fn is_ancestor_of(&self, maybe_ancestor: ID, node_id: ID) -> bool {
-        if maybe_ancestor == node_id {
-            return true;
+    /// Remove `node_id` from the children of `parent`
+    fn unlink(&mut self, node_id: ID, parent: Option<ID>) {
+        let order = self.map[&node_id].order();
+        match parent {
+            Some(parent) => {
+                if let Some(siblings) = self.children.get_mut(&parent) {
+                    siblings.remove(order);
+                    if siblings.is_empty() {
+                        self.children.remove(&parent);
+                    }
+                }
+            }
+            None => {
+                self.roots.remove(order);
+            }
         }
+    }
 
-        let mut node_id = node_id;
-        loop {
-            let node = self.map.get(&node_id).unwrap();
-            match node.parent {
-                Some(parent_id) if parent_id == maybe_ancestor => return true,
-                Some(parent_id) if parent_id == node_id => panic!("loop detected"),
-                Some(parent_id) => {
-                    node_id = parent_id;
+    /// Insert `node_id` into the children of `parent`. Append it if `index` is `None`.
+    ///
+    /// The node must be contained in the map.
+    fn link(&mut self, node_id: ID, parent: Option<ID>, index: Option<usize>) {
+        let siblings = match parent {
+            Some(parent) => self.children.entry(parent).or_default(),
+            None => &mut self.roots,
+        };
+        let index = index.unwrap_or(siblings.len());
+        let order = match siblings.insert(index, node_id) {
+            Some(order) => order,
+            None => {
+                siblings.respace();
+                for (order, id) in siblings.entries() {
+                    self.map.get_mut(id).unwrap().set_order(order);
                 }
-                None => return false,
+                siblings.insert(index, node_id).unwrap()
             }
+        };
+        self.map.get_mut(&node_id).unwrap().set_order(order);
+        self.journal.record(node_id, self.map.len());
+    }
+
+    fn is_ancestor_of(&mut self, maybe_ancestor: ID, node_id: ID) -> Result<bool, Error<ID>> {
+        if maybe_ancestor == node_id {
+            return Ok(true);
+        }
+
+        if let Some(ancestor_index) = &mut self.ancestor_index {
+            // copying a shared index is left to the accepted moves
+            return Ok(match Arc::get_mut(ancestor_index) {
+                Some(index) => index.is_ancestor(maybe_ancestor, node_id),
+                None => ancestor_index.is_ancestor_without_splay(maybe_ancestor, node_id),
+            });
+        }
+
+        let mut ancestors = self.ancestors(node_id);
+        if ancestors.any(|x| x == maybe_ancestor) {
+            return Ok(true);
+        }
+
+        if ancestors.corrupted {
+            return Err(Error::CorruptedHierarchy);
         }
+
+        Ok(false)
     }
 
-    pub fn delete(&mut self, node_id: ID) {
-        self.map.get_mut(&node_id).unwrap().deleted = true;
+    pub fn delete(&mut self, node_id: ID) -> Result<(), Error<ID>> {
+        self.set_deleted(node_id, true)
     }
 
-    pub fn undo_delete(&mut self, node_id: ID) {
-        self.map.get_mut(&node_id).unwrap().deleted = false;
+    pub fn undo_delete(&mut self, node_id: ID) -> Result<(), Error<ID>> {
+        self.set_deleted(node_id, false)
     }
 
-    pub(crate) fn get(&self, id: &ID) -> Option<&TreeNode<ID>> {
+    fn set_deleted(&mut self, node_id: ID, deleted: bool) -> Result<(), Error<ID>> {
+        match self.map.get_mut(&node_id) {
+            Some(node) => {
+                node.set_deleted(deleted);
+                self.journal.record(node_id, self.map.len());
+                Ok(())
+            }
+            None => Err(Error::NodeNotFound(node_id)),
+        }
+    }
+
+    /// Iterate over the children of the given node in order in O(children).
+    ///
+    /// Deleted children are included.
+    pub fn children(&self, node_id: ID) -> impl Iterator<Item = ID> + '_ {
+        self.children_of(Some(node_id))
+    }
+
+    /// Iterate over the children of `parent` in order. `None` stands for the roots.
+    pub(crate) fn children_of(&self, parent: Option<ID>) -> impl Iterator<Item = ID> + '_ {
+        self.siblings(parent)
+            .into_iter()
+            .flat_map(|x| x.iter().copied())
+    }
+
+    /// The ordered children of `parent`. `None` stands for the roots.
+    ///
+    /// Return `None` if a node has no children.
+    fn siblings(&self, parent: Option<ID>) -> Option<&OrderTree<ID>> {
+        match parent {
+            Some(parent) => self.children.get(&parent),
+            None => Some(&self.roots),
+        }
+    }
+
+    /// The number of children of `parent`. `None` stands for the roots.
+    pub fn children_len(&self, parent: Option<ID>) -> usize {
+        self.siblings(parent).map(|x| x.len()).unwrap_or(0)
+    }
+
+    /// The `index`-th child of `parent`. `None` stands for the roots.
+    pub fn child_at(&self, parent: Option<ID>, index: usize) -> Option<ID> {
+        self.siblings(parent)?.get(index).copied()
+    }
+
+    /// The number of leading children of `parent` that satisfy `pred`, in O(log n).
+    /// The children satisfying it must come first.
+    pub(crate) fn children_partition_point(
+        &self,
+        parent: Option<ID>,
+        mut pred: impl FnMut(ID) -> bool,
+    ) -> usize {
+        self.siblings(parent)
+            .map(|x| x.partition_point(|id| pred(*id)))
+            .unwrap_or(0)
+    }
+
+    /// The index of the node among its siblings, including the deleted ones, in O(log n).
+    ///
+    /// Return `None` if the node is not contained in the forest.
+    pub fn index_in_parent(&self, node_id: ID) -> Option<usize> {
+        let node = self.map.get(&node_id)?;
+        self.siblings(node.parent)?.index_of(node.order())
+    }
+
+    /// Get the parent of the node.
+    ///
+    /// Return `None` if the node is a root or is not contained in the forest.
+    pub fn parent_of(&self, node_id: ID) -> Option<ID> {
+        self.map.get(&node_id).and_then(|x| x.parent)
+    }
+
+    /// Whether the node is contained in the forest. Deleted nodes are still contained.
+    pub fn contains(&self, node_id: ID) -> bool {
+        self.map.contains_key(&node_id)
+    }
+
+    /// Whether the node is deleted. Return false if the node is not contained in the forest.
+    pub fn is_deleted(&self, node_id: ID) -> bool {
+        self.map
+            .get(&node_id)
+            .map(|x| x.is_deleted())
+            .unwrap_or(false)
+    }
+
+    /// Iterate over the root nodes in order, including the deleted ones.
+    pub fn roots(&self) -> impl Iterator<Item = ID> + '_ {
+        self.roots.iter().copied()
+    }
+
+    /// The number of nodes in the forest, including the deleted ones.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Iterate over all the nodes in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (&ID, &TreeNode<ID, V>)> + '_ {
+        self.map.iter()
+    }
+
+    /// Get the node by id. Return `None` if it's not contained in the forest.
+    pub fn get(&self, id: &ID) -> Option<&TreeNode<ID, V>> {
         self.map.get(id)
     }
+
+    /// Get the value of the node. Return `None` if it's not contained in the forest.
+    pub fn get_value(&self, node_id: ID) -> Option<&V> {
+        self.map.get(&node_id).map(|x| &x.value)
+    }
+
+    /// Replace the value of the node and return the old one.
+    pub fn set_value(&mut self, node_id: ID, value: V) -> Result<V, Error<ID>> {
+        self.map
+            .get_mut(&node_id)
+            .map(|x| std::mem::replace(&mut x.value, value))
+            .ok_or(Error::NodeNotFound(node_id))
+    }
+
+    /// Update the value of the node in place.
+    ///
+    /// Only the path to the node is copied, the other versions of the forest are not affected.
+    pub fn update_value(&mut self, node_id: ID, f: impl FnOnce(&mut V)) -> Result<(), Error<ID>> {
+        match self.map.get_mut(&node_id) {
+            Some(node) => {
+                f(&mut node.value);
+                Ok(())
+            }
+            None => Err(Error::NodeNotFound(node_id)),
+        }
+    }
 }
 
-impl<ID: IdTrait> Default for Forest<ID> {
+impl<ID: IdTrait, V: Clone> Default for Forest<ID, V> {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sorted_children(forest: &Forest<usize>, id: usize) -> Vec<usize> {
+        let mut ans: Vec<usize> = forest.children(id).collect();
+        ans.sort();
+        ans
+    }
+
+    #[test]
+    fn children_index() {
+        let mut forest: Forest<usize> = Forest::new();
+        forest.mov(1, None).unwrap();
+        forest.mov(2, Some(1)).unwrap();
+        forest.mov(3, Some(1)).unwrap();
+        assert_eq!(sorted_children(&forest, 1), vec![2, 3]);
+
+        let old = forest.clone();
+        forest.mov(2, Some(3)).unwrap();
+        assert_eq!(sorted_children(&forest, 1), vec![3]);
+        assert_eq!(sorted_children(&forest, 3), vec![2]);
+        assert_eq!(sorted_children(&old, 1), vec![2, 3]);
+        assert!(forest.mov(1, Some(2)).is_err());
+        assert_eq!(sorted_children(&forest, 2), Vec::<usize>::new());
+
+        forest.mov(3, None).unwrap();
+        assert_eq!(sorted_children(&forest, 1), Vec::<usize>::new());
+        forest.delete(2).unwrap();
+        assert_eq!(sorted_children(&forest, 3), vec![2]);
+    }
+
+    #[test]
+    fn query() {
+        let mut forest: Forest<usize> = Forest::new();
+        assert!(forest.is_empty());
+        forest.mov(1, None).unwrap();
+        forest.mov(2, Some(1)).unwrap();
+        forest.mov(3, None).unwrap();
+        forest.delete(3).unwrap();
+        assert_eq!(forest.len(), 3);
+        assert_eq!(forest.parent_of(2), Some(1));
+        assert_eq!(forest.parent_of(1), None);
+        assert!(forest.contains(3));
+        assert!(!forest.contains(4));
+        assert!(forest.is_deleted(3));
+        assert!(!forest.is_deleted(2));
+        let mut roots: Vec<usize> = forest.roots().collect();
+        roots.sort();
+        assert_eq!(roots, vec![1, 3]);
+        assert_eq!(forest.iter().filter(|(_, x)| x.is_deleted()).count(), 1);
+    }
+
+    #[test]
+    fn traverse() {
+        //     1     5
+        //    / \
+        //   2   3
+        //   |
+        //   4
+        let mut forest: Forest<usize> = Forest::new();
+        forest.mov(1, None).unwrap();
+        forest.mov(2, Some(1)).unwrap();
+        forest.mov(3, Some(1)).unwrap();
+        forest.mov(4, Some(2)).unwrap();
+        forest.mov(5, None).unwrap();
+
+        assert_eq!(forest.ancestors(4).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(forest.ancestors(1).count(), 0);
+
+        let mut descendants: Vec<usize> = forest.descendants(1).collect();
+        descendants.sort();
+        assert_eq!(descendants, vec![2, 3, 4]);
+
+        let pre: Vec<usize> = forest.pre_order(Some(1)).collect();
+        assert_eq!(pre[0], 1);
+        assert!(pre.iter().position(|&x| x == 2) < pre.iter().position(|&x| x == 4));
+        assert_eq!(forest.pre_order(None).count(), 5);
+
+        let post: Vec<usize> = forest.post_order(Some(1)).collect();
+        assert_eq!(post.len(), 4);
+        assert_eq!(post[3], 1);
+        assert!(post.iter().position(|&x| x == 4) < post.iter().position(|&x| x == 2));
+
+        let mut bfs: Vec<(usize, usize)> = forest.bfs(None).collect();
+        bfs.sort();
+        assert_eq!(bfs, vec![(1, 0), (2, 1), (3, 1), (4, 2), (5, 0)]);
+
+        forest.delete(2).unwrap();
+        let mut alive: Vec<usize> = forest.pre_order(None).skip_deleted().collect();
+        alive.sort();
+        assert_eq!(alive, vec![1, 3, 5]);
+        assert_eq!(forest.post_order(Some(2)).skip_deleted().count(), 0);
+        assert_eq!(forest.bfs(Some(1)).skip_deleted().count(), 2);
+        assert_eq!(
+            forest.ancestors(4).skip_deleted().collect::<Vec<_>>(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn values() {
+        let mut forest: Forest<usize, String> = Forest::new();
+        forest.mov(1, None).unwrap();
+        forest.mov(2, Some(1)).unwrap();
+        assert_eq!(forest.get_value(1), Some(&String::new()));
+        assert_eq!(forest.set_value(1, "a".into()), Ok(String::new()));
+        assert_eq!(forest.set_value(3, "c".into()), Err(Error::NodeNotFound(3)));
+        let old = forest.clone();
+        forest.update_value(1, |v| v.push('b')).unwrap();
+        assert!(forest.update_value(3, |v| v.push('b')).is_err());
+        forest.mov(1, Some(2)).unwrap_err();
+        forest.mov(2, None).unwrap();
+        forest.mov(1, Some(2)).unwrap();
+        assert_eq!(forest.get_value(1).unwrap(), "ab");
+        assert_eq!(old.get_value(1).unwrap(), "a");
+        assert_ne!(old, forest);
+    }
+
+    #[test]
+    fn errors() {
+        let mut forest: Forest<usize> = Forest::new();
+        forest.mov(1, None).unwrap();
+        assert_eq!(forest.mov(2, Some(3)), Err(Error::ParentNotFound(3)));
+        assert!(!forest.contains(2));
+        assert_eq!(forest.mov(1, Some(1)), Err(Error::CyclicMoveErr));
+        assert_eq!(forest.delete(2), Err(Error::NodeNotFound(2)));
+        assert_eq!(forest.undo_delete(2), Err(Error::NodeNotFound(2)));
+
+        // corrupt the hierarchy on purpose
+        forest.mov(2, Some(1)).unwrap();
+        forest.mov(3, None).unwrap();
+        forest.map.get_mut(&1).unwrap().parent = Some(2);
+        assert_eq!(forest.mov(3, Some(2)), Err(Error::CorruptedHierarchy));
+        assert!(forest.ancestors(2).count() <= forest.len());
+        assert_eq!(
+            forest.enable_ancestor_index(),
+            Err(Error::CorruptedHierarchy)
+        );
+    }
+
+    #[test]
+    fn ancestor_index() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut plain: Forest<usize> = Forest::new();
+        for i in 0..30 {
+            plain.mov(i, None).unwrap();
+        }
+        let mut indexed = plain.clone();
+        indexed.enable_ancestor_index().unwrap();
+        let mut mut_indexed: crate::mut_tree::Forest<usize> = crate::mut_tree::Forest::new();
+        mut_indexed.enable_ancestor_index().unwrap();
+        for i in 0..30 {
+            mut_indexed.mov(i, None).unwrap();
+        }
+        for _ in 0..3000 {
+            let node = rng.gen_range(0..40);
+            let parent = if rng.gen_bool(0.1) {
+                None
+            } else {
+                Some(rng.gen_range(0..30))
+            };
+            let old = indexed.clone();
+            let ans = plain.mov(node, parent);
+            assert_eq!(indexed.mov(node, parent), ans);
+            assert_eq!(mut_indexed.mov(node, parent).is_ok(), ans.is_ok());
+            assert_eq!(plain, indexed);
+            if ans.is_ok() {
+                // the clone keeps its own copy of the index
+                assert_eq!(old.clone().mov(node, parent), ans);
+            } else {
+                // a rejected move doesn't copy the shared index
+                let (a, b) = (&old.ancestor_index, &indexed.ancestor_index);
+                assert!(Arc::ptr_eq(a.as_ref().unwrap(), b.as_ref().unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn ordered_children() {
+        let mut forest: Forest<usize> = Forest::new();
+        forest.mov(0, None).unwrap();
+        for i in 1..=4 {
+            forest.mov(i, Some(0)).unwrap();
+        }
+        let children = |f: &Forest<usize>| f.children(0).collect::<Vec<_>>();
+        assert_eq!(children(&forest), vec![1, 2, 3, 4]);
+
+        let old = forest.clone();
+        forest.mov_to(4, Some(0), 0).unwrap();
+        assert_eq!(children(&forest), vec![4, 1, 2, 3]);
+        assert_eq!(children(&old), vec![1, 2, 3, 4]);
+        // moving into the same parent keeps the position
+        forest.mov(4, Some(0)).unwrap();
+        assert_eq!(forest.index_in_parent(4), Some(0));
+
+        forest.move_after(4, 2).unwrap();
+        assert_eq!(children(&forest), vec![1, 2, 4, 3]);
+        forest.move_before(1, 3).unwrap();
+        assert_eq!(children(&forest), vec![2, 4, 1, 3]);
+        forest.move_before(3, 2).unwrap();
+        assert_eq!(children(&forest), vec![3, 2, 4, 1]);
+        assert_eq!(
+            forest.mov_to(3, Some(0), 4),
+            Err(Error::IndexOutOfBound { index: 4, len: 3 })
+        );
+        assert_eq!(forest.move_before(0, 3), Err(Error::CyclicMoveErr));
+
+        forest.mov_to(5, Some(0), 1).unwrap();
+        assert_eq!(children(&forest), vec![3, 5, 2, 4, 1]);
+        forest.mov_to(2, None, 0).unwrap();
+        assert_eq!(forest.roots().collect::<Vec<_>>(), vec![2, 0]);
+        assert_eq!(
+            forest.pre_order(None).collect::<Vec<_>>(),
+            vec![2, 0, 3, 5, 4, 1]
+        );
+        assert_eq!(
+            forest.post_order(None).collect::<Vec<_>>(),
+            vec![2, 3, 5, 4, 1, 0]
+        );
+        assert_ne!(old, forest);
+    }
+
+    #[test]
+    fn insert_at_same_spot() {
+        // there's soon no room between the keys of the first two roots, so they are respaced
+        let mut forest: Forest<usize> = Forest::new();
+        let mut roots = Vec::new();
+        for i in 0..200 {
+            let index = roots.len().min(1);
+            forest.mov_to(i, None, index).unwrap();
+            roots.insert(index, i);
+        }
+        let old = forest.clone();
+        forest.move_after(0, 150).unwrap();
+        assert_eq!(old.roots().collect::<Vec<_>>(), roots);
+        roots.retain(|x| *x != 0);
+        roots.insert(roots.iter().position(|x| *x == 150).unwrap() + 1, 0);
+        assert_eq!(forest.roots().collect::<Vec<_>>(), roots);
+        for (index, id) in roots.iter().enumerate() {
+            assert_eq!(forest.index_in_parent(*id), Some(index));
+            assert_eq!(forest.child_at(None, index), Some(*id));
+        }
+    }
+
+    #[test]
+    fn node_size() {
+        // the key of a node shares its word with the deleted flag
+        assert_eq!(
+            std::mem::size_of::<TreeNode<usize>>(),
+            std::mem::size_of::<(Option<usize>, bool)>()
+        );
+        let mut node: TreeNode<usize> = TreeNode::new(None, true, ());
+        node.set_order(MAX_KEY);
+        assert!(node.is_deleted());
+        assert_eq!(node.order(), MAX_KEY);
+        node.set_deleted(false);
+        assert_eq!(node.order(), MAX_KEY);
+    }
+
+    #[test]
+    fn diff() {
+        let mut forest: Forest<usize> = Forest::new();
+        for i in 0..10_000 {
+            forest.mov(i, None).unwrap();
+        }
+        forest.delete(3).unwrap();
+        let old = forest.clone();
+        forest.mov(1, Some(0)).unwrap();
+        forest.delete(2).unwrap();
+        forest.undo_delete(3).unwrap();
+        forest.mov(10_000, Some(1)).unwrap();
+        forest.mov(10_001, None).unwrap();
+        forest.delete(10_001).unwrap();
+        // reordering is not reported
+        forest.mov_to(4, None, 0).unwrap();
+
+        let changes = Forest::diff(&old, &forest);
+        assert_eq!(
+            changes,
+            vec![
+                Change::Moved {
+                    node: 1,
+                    from: None,
+                    to: Some(0)
+                },
+                Change::Deleted(2),
+                Change::Restored(3),
+                Change::Created {
+                    node: 10_000,
+                    parent: Some(1)
+                },
+                Change::Created {
+                    node: 10_001,
+                    parent: None
+                },
+                Change::Deleted(10_001),
+            ]
+        );
+        assert!(Forest::diff(&forest, &forest.clone()).is_empty());
+        let back = Forest::diff(&forest, &old);
+        assert!(back.contains(&Change::Removed(10_000)));
+        assert!(back.contains(&Change::Restored(2)));
+    }
+
+    #[test]
+    fn diff_journal() {
+        let mut base: Forest<usize> = Forest::new();
+        for i in 0..1000 {
+            base.mov(i, None).unwrap();
+        }
+        // both versions are derived from base
+        let mut a = base.clone();
+        a.mov(1, Some(0)).unwrap();
+        a.delete(2).unwrap();
+        let mut b = base.clone();
+        b.mov(3, Some(0)).unwrap();
+        let mut changes = Forest::diff(&a, &b);
+        changes.sort_by_key(|x| match x {
+            Change::Moved { node, .. } | Change::Restored(node) => *node,
+            _ => unreachable!(),
+        });
+        assert_eq!(
+            changes,
+            vec![
+                Change::Moved {
+                    node: 1,
+                    from: Some(0),
+                    to: None
+                },
+                Change::Restored(2),
+                Change::Moved {
+                    node: 3,
+                    from: None,
+                    to: Some(0)
+                },
+            ]
+        );
+
+        // the journal forgets the old edits, so every node is compared
+        let mut c = base.clone();
+        for _ in 0..3 {
+            for i in 1..1000 {
+                c.mov(i, Some(0)).unwrap();
+                c.mov(i, None).unwrap();
+            }
+        }
+        c.delete(5).unwrap();
+        assert_eq!(Forest::diff(&base, &c), vec![Change::Deleted(5)]);
+        assert_eq!(Forest::diff(&c, &base), vec![Change::Restored(5)]);
+
+        // the forests built separately are compared node by node too
+        let mut other: Forest<usize> = Forest::new();
+        for i in (0..1000).rev() {
+            other.mov(i, None).unwrap();
+        }
+        other.mov(3, Some(0)).unwrap();
+        assert!(Forest::diff(&b, &other).is_empty());
+        // 1000 nodes are created and one of them is deleted
+        assert_eq!(Forest::diff(&Forest::new(), &a).len(), 1001);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let mut forest: Forest<usize, String> = Forest::new();
+        forest.mov(1, None).unwrap();
+        forest.mov(0, None).unwrap();
+        forest.mov(3, Some(1)).unwrap();
+        forest.mov(2, Some(1)).unwrap();
+        forest.set_value(2, "two".into()).unwrap();
+        forest.delete(3).unwrap();
+        let json = serde_json::to_string(&forest).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Forest<usize, String>>(&json).unwrap(),
+            forest
+        );
+        let bytes = rmp_serde::to_vec(&forest).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<Forest<usize, String>>(&bytes).unwrap(),
+            forest
+        );
+
+        let mut mut_forest: crate::mut_tree::Forest<usize, String> = Default::default();
+        for id in forest.pre_order(None) {
+            let index = forest.index_in_parent(id).unwrap();
+            mut_forest.mov_to(id, forest.parent_of(id), index).unwrap();
+            mut_forest
+                .set_value(id, forest.get_value(id).unwrap().clone())
+                .unwrap();
+        }
+        mut_forest.delete(3).unwrap();
+        let json = serde_json::to_string(&mut_forest).unwrap();
+        assert_eq!(
+            serde_json::from_str::<crate::mut_tree::Forest<usize, String>>(&json).unwrap(),
+            mut_forest
+        );
+        assert_eq!(
+            serde_json::from_str::<Forest<usize, String>>(&json).unwrap(),
+            forest
+        );
+
+        let parse = |json: &str| serde_json::from_str::<Forest<usize>>(json);
+        let ok = r#"[{"id":2,"parent":1,"value":null},{"id":1,"parent":null,"value":null}]"#;
+        assert_eq!(parse(ok).unwrap().parent_of(2), Some(1));
+        let dangling = r#"[{"id":1,"parent":3,"value":null}]"#;
+        assert!(parse(dangling).is_err());
+        let cycle = r#"[{"id":0,"parent":null,"value":null},{"id":1,"parent":2,"value":null},{"id":2,"parent":1,"value":null}]"#;
+        assert!(parse(cycle).is_err());
+        let self_loop = r#"[{"id":1,"parent":1,"value":null}]"#;
+        assert!(parse(self_loop).is_err());
+        let duplicate =
+            r#"[{"id":1,"parent":null,"value":null},{"id":1,"parent":null,"value":null}]"#;
+        assert!(parse(duplicate).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use fxhash::FxHashSet;
+
+use super::{Forest, IdTrait, TreeNode};
+
+/// A change of a node between two versions of a [`Forest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<ID> {
+    /// The node is contained in the new version only
+    Created {
+        node: ID,
+        parent: Option<ID>,
+    },
+    /// The parent of the node changed
+    Moved {
+        node: ID,
+        from: Option<ID>,
+        to: Option<ID>,
+    },
+    Deleted(ID),
+    Restored(ID),
+    /// The node is contained in the old version only.
+    ///
+    /// It only happens when the "new" version is older than the "old" one.
+    Removed(ID),
+}
+
+/// The nodes touched by the edits of a forest, newest first.
+///
+/// A clone shares the entries of the forest it's cloned from, so the edits between two
+/// versions can be found by walking back from both to the entry they share.
+///
+/// The journal forgets the older entries once they outnumber a quarter of the nodes,
+/// so it takes little memory. Comparing every node is about as fast then.
+#[derive(Clone)]
+pub(crate) struct Journal<ID> {
+    head: Option<Arc<Entry<ID>>>,
+}
+
+struct Entry<ID> {
+    /// `None` marks where older entries were forgotten
+    node: Option<ID>,
+    /// The number of entries after the last mark
+    len: usize,
+    prev: Option<Arc<Entry<ID>>>,
+}
+
+impl<ID> Default for Journal<ID> {
+    fn default() -> Self {
+        Self { head: None }
+    }
+}
+
+/// Drop a long chain of entries without recursion
+impl<ID> Drop for Entry<ID> {
+    fn drop(&mut self) {
+        let mut prev = self.prev.take();
+        while let Some(entry) = prev {
+            match Arc::try_unwrap(entry) {
+                Ok(mut entry) => prev = entry.prev.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+fn len<ID>(link: &Option<Arc<Entry<ID>>>) -> usize {
+    link.as_ref().map(|x| x.len).unwrap_or(0)
+}
+
+impl<ID: Copy> Journal<ID> {
+    /// Record an edit of `node` in a forest with `forest_len` nodes
+    pub fn record(&mut self, node: ID, forest_len: usize) {
+        if len(&self.head) > forest_len / 4 + 64 {
+            self.head = Some(Arc::new(Entry {
+                node: None,
+                len: 0,
+                prev: None,
+            }));
+        }
+
+        let prev = self.head.take();
+        self.head = Some(Arc::new(Entry {
+            node: Some(node),
+            len: len(&prev) + 1,
+            prev,
+        }));
+    }
+
+    /// The nodes touched since the last entry shared by `a` and `b`, oldest first.
+    ///
+    /// Return `None` if the shared entry has been forgotten, or the forests are not related.
+    /// Two forests built from empty ones are related.
+    fn touched_since_shared(a: &Self, b: &Self) -> Option<Vec<ID>> {
+        let mut a = &a.head;
+        let mut b = &b.head;
+        let mut nodes = Vec::new();
+        loop {
+            match (a, b) {
+                (None, None) => break,
+                (Some(x), Some(y)) if Arc::ptr_eq(x, y) => break,
+                _ => {}
+            }
+
+            let (len_a, len_b) = (len(a), len(b));
+            if len_a >= len_b {
+                let entry = a.as_ref()?;
+                nodes.push(entry.node?);
+                a = &entry.prev;
+            }
+            if len_b >= len_a {
+                let entry = b.as_ref()?;
+                nodes.push(entry.node?);
+                b = &entry.prev;
+            }
+        }
+
+        nodes.reverse();
+        Some(nodes)
+    }
+}
+
+impl<ID: IdTrait, V: Clone + PartialEq> Forest<ID, V> {
+    /// The changes from `old` to `new`.
+    ///
+    /// Every forest keeps a journal of the nodes its edits touched, and clones share it.
+    /// So when `new` is derived from `old` (or the other way around, or both from a common
+    /// version), only the nodes touched since the common version are compared. It takes
+    /// time proportional to the changes rather than to the size of the forest.
+    /// The nodes are listed in the order they were first touched then.
+    ///
+    /// Otherwise, or when the edits outnumber a quarter of the nodes, every node is
+    /// compared, in arbitrary order.
+    ///
+    /// A created node that is also deleted yields both `Created` and `Deleted`.
+    /// Only the hierarchy and the deletion are reported: reordering the siblings
+    /// and updating the values are not.
+    pub fn diff(old: &Self, new: &Self) -> Vec<Change<ID>> {
+        let nodes =
+            Journal::touched_since_shared(&old.journal, &new.journal).unwrap_or_else(|| {
+                old.map
+                    .keys()
+                    .copied()
+                    .chain(new.map.keys().filter(|x| !old.map.contains_key(x)).copied())
+                    .collect()
+            });
+
+        let mut ans = Vec::new();
+        let mut visited: FxHashSet<ID> = Default::default();
+        for id in nodes {
+            if visited.insert(id) {
+                diff_node(id, old.map.get(&id), new.map.get(&id), &mut ans);
+            }
+        }
+
+        ans
+    }
+}
+
+/// Push the changes of a node from `old` to `new`
+fn diff_node<ID: Copy + PartialEq, V>(
+    id: ID,
+    old: Option<&TreeNode<ID, V>>,
+    new: Option<&TreeNode<ID, V>>,
+    ans: &mut Vec<Change<ID>>,
+) {
+    match (old, new) {
+        (None, Some(node)) => {
+            ans.push(Change::Created {
+                node: id,
+                parent: node.parent,
+            });
+            if node.is_deleted() {
+                ans.push(Change::Deleted(id));
+            }
+        }
+        (Some(old_node), Some(new_node)) => {
+            if old_node.parent != new_node.parent {
+                ans.push(Change::Moved {
+                    node: id,
+                    from: old_node.parent,
+                    to: new_node.parent,
+                });
+            }
+            match (old_node.is_deleted(), new_node.is_deleted()) {
+                (false, true) => ans.push(Change::Deleted(id)),
+                (true, false) => ans.push(Change::Restored(id)),
+                _ => {}
+            }
+        }
+        (Some(_), None) => ans.push(Change::Removed(id)),
+        (None, None) => {}
+    }
+}
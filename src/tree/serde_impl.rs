@@ -0,0 +1,104 @@
+//! A forest is serialized as a sequence of nodes. Siblings appear in their order,
+//! so the order of children is kept.
+//!
+//! Deserializing checks that the ids are unique, every parent exists and the parents don't form a cycle.
+use std::{fmt::Debug, hash::Hash};
+
+use fxhash::FxHashMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{Error, Forest, IdTrait, TreeNode};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SerNode<ID, V> {
+    pub id: ID,
+    pub parent: Option<ID>,
+    #[serde(default)]
+    pub deleted: bool,
+    pub value: V,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SerNodeRef<'a, ID, V> {
+    pub id: ID,
+    pub parent: Option<ID>,
+    pub deleted: bool,
+    pub value: &'a V,
+}
+
+/// Check the hierarchy described by the nodes.
+pub(crate) fn validate<ID: Hash + Eq + Copy + Debug, V, E: serde::de::Error>(
+    nodes: &[SerNode<ID, V>],
+) -> Result<(), E> {
+    let mut parents: FxHashMap<ID, Option<ID>> = Default::default();
+    for node in nodes {
+        if parents.insert(node.id, node.parent).is_some() {
+            return Err(E::custom(format!("duplicate node id {:?}", node.id)));
+        }
+    }
+
+    for node in nodes {
+        if let Some(parent) = node.parent {
+            if !parents.contains_key(&parent) {
+                return Err(E::custom(Error::ParentNotFound(parent)));
+            }
+        }
+    }
+
+    // the nodes known to reach a root
+    let mut done: FxHashMap<ID, ()> = Default::default();
+    let mut path = Vec::new();
+    for node in nodes {
+        let mut id = node.id;
+        loop {
+            if done.contains_key(&id) {
+                break;
+            }
+            if path.len() > parents.len() {
+                return Err(E::custom(Error::<ID>::CorruptedHierarchy));
+            }
+            path.push(id);
+            match parents[&id] {
+                Some(parent) => id = parent,
+                None => break,
+            }
+        }
+        for id in path.drain(..) {
+            done.insert(id, ());
+        }
+    }
+
+    Ok(())
+}
+
+impl<ID: IdTrait + Serialize, V: Clone + Serialize> Serialize for Forest<ID, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.pre_order(None).map(|id| {
+            let node = &self.map[&id];
+            SerNodeRef {
+                id,
+                parent: node.parent,
+                deleted: node.is_deleted(),
+                value: &node.value,
+            }
+        }))
+    }
+}
+
+impl<'de, ID: IdTrait + Deserialize<'de>, V: Clone + Deserialize<'de>> Deserialize<'de>
+    for Forest<ID, V>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let nodes: Vec<SerNode<ID, V>> = Vec::deserialize(deserializer)?;
+        validate::<_, _, D::Error>(&nodes)?;
+        let mut forest = Forest::new();
+        for node in nodes {
+            forest.map.insert(
+                node.id,
+                TreeNode::new(node.parent, node.deleted, node.value),
+            );
+            forest.link(node.id, node.parent, None);
+        }
+        Ok(forest)
+    }
+}
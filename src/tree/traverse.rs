@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+
+use super::{Forest, IdTrait};
+
+/// Iterator over the ancestors of a node, from its parent up to the root.
+///
+/// If the parent pointers are corrupted, the iteration stops early instead of looping forever.
+///
+/// Created by [`Forest::ancestors`].
+pub struct Ancestors<'a, ID, V = ()> {
+    forest: &'a Forest<ID, V>,
+    current: Option<ID>,
+    skip_deleted: bool,
+    /// The number of parent pointers followed so far
+    steps: usize,
+    /// Set when the walk stopped at a loop or at a missing node
+    pub(super) corrupted: bool,
+}
+
+/// Depth-first pre-order iterator. Parents are visited before their children.
+///
+/// Created by [`Forest::pre_order`] and [`Forest::descendants`].
+pub struct PreOrder<'a, ID, V = ()> {
+    forest: &'a Forest<ID, V>,
+    stack: Vec<ID>,
+    skip_deleted: bool,
+}
+
+/// Depth-first post-order iterator. Children are visited before their parents.
+///
+/// Created by [`Forest::post_order`].
+pub struct PostOrder<'a, ID, V = ()> {
+    forest: &'a Forest<ID, V>,
+    /// The flag is true when the children of the node have been pushed
+    stack: Vec<(ID, bool)>,
+    skip_deleted: bool,
+}
+
+/// Breadth-first iterator that yields each node with its depth.
+///
+/// The depth of the start node (or of the roots) is 0.
+///
+/// Created by [`Forest::bfs`].
+pub struct Bfs<'a, ID, V = ()> {
+    forest: &'a Forest<ID, V>,
+    queue: VecDeque<(ID, usize)>,
+    skip_deleted: bool,
+}
+
+impl<ID: IdTrait, V: Clone> Forest<ID, V> {
+    /// Iterate over the ancestors of the node, starting from its parent.
+    pub fn ancestors(&self, node_id: ID) -> Ancestors<'_, ID, V> {
+        Ancestors {
+            forest: self,
+            current: Some(node_id),
+            skip_deleted: false,
+            steps: 0,
+            corrupted: false,
+        }
+    }
+
+    /// Iterate over the descendants of the node in pre-order. The node itself is excluded.
+    pub fn descendants(&self, node_id: ID) -> PreOrder<'_, ID, V> {
+        let mut stack = Vec::new();
+        self.push_children(&mut stack, Some(node_id));
+        PreOrder {
+            forest: self,
+            stack,
+            skip_deleted: false,
+        }
+    }
+
+    /// Depth-first pre-order traversal.
+    ///
+    /// If `start` is `None`, it walks the whole forest. Otherwise it walks the
+    /// subtree rooted at `start`, including `start`.
+    pub fn pre_order(&self, start: Option<ID>) -> PreOrder<'_, ID, V> {
+        PreOrder {
+            forest: self,
+            stack: self.start_nodes(start),
+            skip_deleted: false,
+        }
+    }
+
+    /// Depth-first post-order traversal.
+    ///
+    /// If `start` is `None`, it walks the whole forest. Otherwise it walks the
+    /// subtree rooted at `start`, including `start`.
+    pub fn post_order(&self, start: Option<ID>) -> PostOrder<'_, ID, V> {
+        PostOrder {
+            forest: self,
+            stack: self
+                .start_nodes(start)
+                .into_iter()
+                .map(|x| (x, false))
+                .collect(),
+            skip_deleted: false,
+        }
+    }
+
+    /// Breadth-first traversal with depth info.
+    ///
+    /// If `start` is `None`, it walks the whole forest. Otherwise it walks the
+    /// subtree rooted at `start`, including `start`.
+    pub fn bfs(&self, start: Option<ID>) -> Bfs<'_, ID, V> {
+        let mut nodes = self.start_nodes(start);
+        nodes.reverse();
+        Bfs {
+            forest: self,
+            queue: nodes.into_iter().map(|x| (x, 0)).collect(),
+            skip_deleted: false,
+        }
+    }
+
+    /// The stack to start a depth-first walk with. The first node to visit is at the top.
+    fn start_nodes(&self, start: Option<ID>) -> Vec<ID> {
+        match start {
+            Some(id) if self.contains(id) => vec![id],
+            Some(_) => Vec::new(),
+            None => {
+                let mut stack = Vec::new();
+                self.push_children(&mut stack, None);
+                stack
+            }
+        }
+    }
+
+    /// Push the children in reversed order, so they will be popped in order.
+    fn push_children(&self, stack: &mut Vec<ID>, parent: Option<ID>) {
+        if let Some(children) = self.siblings(parent) {
+            let start = stack.len();
+            stack.extend(children.iter().copied());
+            stack[start..].reverse();
+        }
+    }
+}
+
+impl<'a, ID: IdTrait, V: Clone> Ancestors<'a, ID, V> {
+    /// Don't yield the deleted ancestors.
+    pub fn skip_deleted(mut self) -> Self {
+        self.skip_deleted = true;
+        self
+    }
+}
+
+impl<'a, ID: IdTrait, V: Clone> Iterator for Ancestors<'a, ID, V> {
+    type Item = ID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let current = self.current?;
+            let parent = match self.forest.map.get(&current) {
+                Some(node) => node.parent,
+                None => {
+                    // only the start node is allowed to be missing
+                    self.corrupted = self.steps > 0;
+                    None
+                }
+            };
+            self.steps += 1;
+            if parent.is_some() && self.steps > self.forest.map.len() {
+                self.corrupted = true;
+                self.current = None;
+                return None;
+            }
+
+            self.current = parent;
+            match parent {
+                Some(parent) if self.skip_deleted && self.forest.is_deleted(parent) => {}
+                _ => return parent,
+            }
+        }
+    }
+}
+
+impl<'a, ID: IdTrait, V: Clone> PreOrder<'a, ID, V> {
+    /// Skip the deleted nodes and their subtrees.
+    pub fn skip_deleted(mut self) -> Self {
+        self.skip_deleted = true;
+        self
+    }
+}
+
+impl<'a, ID: IdTrait, V: Clone> Iterator for PreOrder<'a, ID, V> {
+    type Item = ID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let id = self.stack.pop()?;
+            if self.skip_deleted && self.forest.is_deleted(id) {
+                continue;
+            }
+
+            self.forest.push_children(&mut self.stack, Some(id));
+            return Some(id);
+        }
+    }
+}
+
+impl<'a, ID: IdTrait, V: Clone> PostOrder<'a, ID, V> {
+    /// Skip the deleted nodes and their subtrees.
+    pub fn skip_deleted(mut self) -> Self {
+        self.skip_deleted = true;
+        self
+    }
+}
+
+impl<'a, ID: IdTrait, V: Clone> Iterator for PostOrder<'a, ID, V> {
+    type Item = ID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (id, expanded) = self.stack.pop()?;
+            if expanded {
+                return Some(id);
+            }
+
+            if self.skip_deleted && self.forest.is_deleted(id) {
+                continue;
+            }
+
+            self.stack.push((id, true));
+            if let Some(children) = self.forest.children.get(&id) {
+                let start = self.stack.len();
+                self.stack.extend(children.iter().map(|x| (*x, false)));
+                self.stack[start..].reverse();
+            }
+        }
+    }
+}
+
+impl<'a, ID: IdTrait, V: Clone> Bfs<'a, ID, V> {
+    /// Skip the deleted nodes and their subtrees.
+    pub fn skip_deleted(mut self) -> Self {
+        self.skip_deleted = true;
+        self
+    }
+}
+
+impl<'a, ID: IdTrait, V: Clone> Iterator for Bfs<'a, ID, V> {
+    type Item = (ID, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (id, depth) = self.queue.pop_front()?;
+            if self.skip_deleted && self.forest.is_deleted(id) {
+                continue;
+            }
+
+            if let Some(children) = self.forest.children.get(&id) {
+                self.queue.extend(children.iter().map(|x| (*x, depth + 1)));
+            }
+
+            return Some((id, depth));
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use fxhash::FxHashMap;
+
+/// The number of ops seen from each client.
+///
+/// The ops of a client are numbered from 0 by their counter, so a replica that has seen
+/// `n` ops of a client has seen exactly the ops whose counter is below `n`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VersionVector(FxHashMap<u64, u32>);
+
+impl VersionVector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of ops seen from the client
+    pub fn get(&self, client: u64) -> u32 {
+        self.0.get(&client).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, client: u64, len: u32) {
+        if len == 0 {
+            self.0.remove(&client);
+        } else {
+            self.0.insert(client, len);
+        }
+    }
+
+    /// Iterate over the clients whose ops have been seen, in arbitrary order
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u32)> + '_ {
+        self.0.iter().map(|(client, len)| (*client, *len))
+    }
+
+    /// Take the maximum of each client
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (client, len) in other.iter() {
+            if len > self.get(client) {
+                self.0.insert(client, len);
+            }
+        }
+    }
+
+    /// Whether every op seen by `other` is also seen by `self`
+    pub fn includes(&self, other: &VersionVector) -> bool {
+        other.iter().all(|(client, len)| self.get(client) >= len)
+    }
+}
+
+impl FromIterator<(u64, u32)> for VersionVector {
+    fn from_iter<T: IntoIterator<Item = (u64, u32)>>(iter: T) -> Self {
+        let mut ans = Self::new();
+        for (client, len) in iter {
+            ans.set(client, len);
+        }
+        ans
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn version_vector() {
+        let mut a: VersionVector = [(1, 3), (2, 0)].into_iter().collect();
+        let b: VersionVector = [(1, 2), (3, 1)].into_iter().collect();
+        assert_eq!(a.get(2), 0);
+        assert_eq!(a.iter().count(), 1);
+        assert!(!a.includes(&b) && !b.includes(&a));
+        a.merge(&b);
+        assert_eq!(a.get(1), 3);
+        assert_eq!(a.get(3), 1);
+        assert!(a.includes(&b));
+    }
+}